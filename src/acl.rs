@@ -0,0 +1,558 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::glob;
+use crate::password::PasswordHash;
+use crate::snapshot;
+
+/**
+ * Name of the user connections start out as, and the one `requirepass` and
+ * `AUTH <password>` refer to.
+ */
+pub const DEFAULT_USER: &str = "default";
+
+/**
+ * Which arguments of a command are keys, checked against a user's key patterns
+ */
+#[derive(Clone, Copy)]
+enum Keys {
+    None,
+    First,
+    All,
+}
+
+struct CommandSpec {
+    name: &'static str,
+    category: &'static str,
+    keys: Keys,
+}
+
+const fn spec(name: &'static str, category: &'static str, keys: Keys) -> CommandSpec {
+    CommandSpec {
+        name,
+        category,
+        keys,
+    }
+}
+
+/**
+ * Every command with the category it can be granted through (`+@read`, ...)
+ */
+const COMMANDS: &[CommandSpec] = &[
+    spec("get", "read", Keys::First),
+    spec("ttl", "read", Keys::First),
+    spec("pttl", "read", Keys::First),
+    spec("expiretime", "read", Keys::First),
+    spec("pexpiretime", "read", Keys::First),
+    spec("strlen", "read", Keys::First),
+    spec("getrange", "read", Keys::First),
+    spec("set", "write", Keys::First),
+    spec("del", "write", Keys::All),
+    spec("expire", "write", Keys::First),
+    spec("pexpire", "write", Keys::First),
+    spec("expireat", "write", Keys::First),
+    spec("pexpireat", "write", Keys::First),
+    spec("persist", "write", Keys::First),
+    spec("append", "write", Keys::First),
+    spec("setrange", "write", Keys::First),
+    spec("getdel", "write", Keys::First),
+    spec("getex", "write", Keys::First),
+    spec("save", "admin", Keys::None),
+    spec("bgsave", "admin", Keys::None),
+    spec("bgrewriteaof", "admin", Keys::None),
+    spec("config", "admin", Keys::None),
+    spec("acl", "admin", Keys::None),
+    spec("shutdown", "admin", Keys::None),
+    spec("auth", "connection", Keys::None),
+    spec("hello", "connection", Keys::None),
+    spec("ping", "connection", Keys::None),
+    spec("quit", "connection", Keys::None),
+];
+
+fn command_spec(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+/**
+ * Why a request was refused
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub enum Denied {
+    Command,
+    Key,
+}
+
+/**
+ * A named user: whether it may log in, with which passwords, and which
+ * commands and keys it may use.
+ */
+#[derive(Clone, Debug)]
+pub struct User {
+    pub name: String,
+    enabled: bool,
+    /// Any password is accepted
+    nopass: bool,
+    /// Only hashes are kept, never the passwords themselves
+    passwords: BTreeSet<PasswordHash>,
+    commands: BTreeSet<&'static str>,
+    key_patterns: Vec<String>,
+}
+
+impl User {
+    /**
+     * A new user is disabled and may do nothing until rules grant it access.
+     */
+    fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            commands: BTreeSet::new(),
+            key_patterns: Vec::new(),
+        }
+    }
+
+    /**
+     * Apply one ACL rule, as accepted by `ACL SETUSER`.
+     */
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let error = |reason: &str| format!("Error in ACL SETUSER modifier '{}': {}", rule, reason);
+
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.key_patterns = vec!["*".to_string()],
+            "resetkeys" => self.key_patterns.clear(),
+            "allcommands" | "+@all" => {
+                self.commands = COMMANDS.iter().map(|spec| spec.name).collect();
+            }
+            "nocommands" | "-@all" => self.commands.clear(),
+            "reset" => *self = User::new(&self.name),
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.nopass = false;
+                    // Every hash has its own salt, so look for the password itself
+                    if !self.check_password(password.as_bytes()) {
+                        self.passwords
+                            .insert(PasswordHash::new(password.as_bytes()));
+                    }
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    let before = self.passwords.len();
+                    self.passwords
+                        .retain(|hash| !hash.verify(password.as_bytes()));
+                    if self.passwords.len() == before {
+                        return Err(error("no such password"));
+                    }
+                } else if rule.starts_with('#') {
+                    let hash = PasswordHash::parse(rule).ok_or_else(|| {
+                        error("The password hash must be '#<salt>:<sha256>' in hex")
+                    })?;
+                    self.nopass = false;
+                    self.passwords.insert(hash);
+                } else if let Some(hash) = rule.strip_prefix('!') {
+                    let hash = PasswordHash::parse(&format!("#{}", hash)).ok_or_else(|| {
+                        error("The password hash must be '!<salt>:<sha256>' in hex")
+                    })?;
+                    if !self.passwords.remove(&hash) {
+                        return Err(error("no such password"));
+                    }
+                } else if let Some(pattern) = rule.strip_prefix('~') {
+                    if !self.key_patterns.iter().any(|existing| existing == pattern) {
+                        self.key_patterns.push(pattern.to_string());
+                    }
+                } else if let Some(name) = rule.strip_prefix('+') {
+                    let commands = resolve_commands(name)
+                        .ok_or_else(|| error("Unknown command or category name in ACL"))?;
+                    self.commands.extend(commands);
+                } else if let Some(name) = rule.strip_prefix('-') {
+                    let commands = resolve_commands(name)
+                        .ok_or_else(|| error("Unknown command or category name in ACL"))?;
+                    for command in commands {
+                        self.commands.remove(command);
+                    }
+                } else {
+                    return Err(error("Syntax error"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_password(&self, password: &[u8]) -> bool {
+        // Check every hash instead of stopping at the first match, so the
+        // time taken doesn't tell which of the passwords was given
+        let matched = self
+            .passwords
+            .iter()
+            .fold(false, |matched, hash| hash.verify(password) | matched);
+        self.nopass || matched
+    }
+
+    /**
+     * Whether connections are logged in as this user without calling AUTH
+     */
+    pub fn needs_no_auth(&self) -> bool {
+        self.enabled && self.nopass
+    }
+
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub fn passwords(&self) -> impl Iterator<Item = &PasswordHash> {
+        self.passwords.iter()
+    }
+
+    /**
+     * The allowed commands as rules, e.g. `+@all` or `-@all +get +ttl`
+     */
+    pub fn command_rules(&self) -> String {
+        if self.commands.len() == COMMANDS.len() {
+            return "+@all".to_string();
+        }
+        let mut rules = vec!["-@all".to_string()];
+        rules.extend(self.commands.iter().map(|command| format!("+{}", command)));
+        rules.join(" ")
+    }
+
+    /**
+     * The key patterns as rules, e.g. `~app:* ~cache:*`
+     */
+    pub fn key_rules(&self) -> String {
+        self.key_patterns
+            .iter()
+            .map(|pattern| format!("~{}", pattern))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /**
+     * All rules needed to recreate this user, as listed by `ACL LIST`
+     */
+    pub fn describe(&self) -> String {
+        let mut rules: Vec<String> = self.flags().iter().map(|flag| flag.to_string()).collect();
+        rules.extend(self.passwords.iter().map(|hash| hash.to_string()));
+        if !self.key_patterns.is_empty() {
+            rules.push(self.key_rules());
+        }
+        rules.push(self.command_rules());
+        rules.join(" ")
+    }
+
+    /**
+     * Check whether this user may run `command` (lowercase) with `args`.
+     * Commands that don't exist are let through, the dispatcher rejects them.
+     */
+    pub fn check(&self, command: &str, args: &[Vec<u8>]) -> Result<(), Denied> {
+        let Some(spec) = command_spec(command) else {
+            return Ok(());
+        };
+        if !self.commands.contains(spec.name) {
+            return Err(Denied::Command);
+        }
+
+        let keys = match spec.keys {
+            Keys::None => &args[..0],
+            Keys::First => &args[1..2],
+            Keys::All => &args[1..],
+        };
+        let allowed = |key: &Vec<u8>| {
+            self.key_patterns
+                .iter()
+                .any(|pattern| glob::matches(pattern.as_bytes(), key))
+        };
+        if keys.iter().all(allowed) {
+            Ok(())
+        } else {
+            Err(Denied::Key)
+        }
+    }
+}
+
+/**
+ * The commands a `+`/`-` rule refers to: a command name or `@category`
+ */
+fn resolve_commands(name: &str) -> Option<Vec<&'static str>> {
+    let name = name.to_lowercase();
+    let commands: Vec<&'static str> = match name.strip_prefix('@') {
+        Some("all") => COMMANDS.iter().map(|spec| spec.name).collect(),
+        Some(category) => COMMANDS
+            .iter()
+            .filter(|spec| spec.category == category)
+            .map(|spec| spec.name)
+            .collect(),
+        None => command_spec(&name)
+            .map(|spec| spec.name)
+            .into_iter()
+            .collect(),
+    };
+    if commands.is_empty() {
+        None
+    } else {
+        Some(commands)
+    }
+}
+
+/**
+ * All users known to the server
+ */
+pub struct Acl {
+    users: BTreeMap<String, User>,
+}
+
+impl Acl {
+    /**
+     * Only the default user, which may do everything and logs in with
+     * `requirepass`, or without a password if that is empty.
+     */
+    pub fn new(requirepass: &str) -> Acl {
+        let mut acl = Acl {
+            users: BTreeMap::new(),
+        };
+        acl.set_user(DEFAULT_USER, &["on", "allkeys", "allcommands"])
+            .unwrap();
+        acl.set_default_password(requirepass);
+        acl
+    }
+
+    /**
+     * Make `requirepass`, a password hash, the only password of the default user
+     */
+    pub fn set_default_password(&mut self, requirepass: &str) {
+        let rules = if requirepass.is_empty() {
+            ["resetpass", "nopass"]
+        } else {
+            ["resetpass", requirepass]
+        };
+        self.set_user(DEFAULT_USER, &rules).unwrap();
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &User> {
+        self.users.values()
+    }
+
+    /**
+     * Whether `name` may log in with `password`
+     */
+    pub fn authenticate(&self, name: &str, password: &[u8]) -> bool {
+        match self.users.get(name) {
+            // Disabled users have their password checked all the same, and
+            // unknown ones a made-up hash, so the response time doesn't give
+            // away which users exist
+            Some(user) => user.check_password(password) & user.enabled,
+            None => {
+                static UNKNOWN_USER: OnceLock<PasswordHash> = OnceLock::new();
+                let hash = UNKNOWN_USER.get_or_init(|| PasswordHash::new(b""));
+                std::hint::black_box(hash.verify(password));
+                false
+            }
+        }
+    }
+
+    /**
+     * Whether `name` may log in at all, for clients that prove who they are
+     * without a password
+     */
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.users.get(name).is_some_and(|user| user.enabled)
+    }
+
+    /**
+     * Create or change a user. Either all rules are applied or, if one of
+     * them is invalid, none.
+     */
+    pub fn set_user(&mut self, name: &str, rules: &[&str]) -> Result<(), String> {
+        let mut user = self
+            .users
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply(rule)?;
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /**
+     * Remove users, returning how many existed. Names are checked before any
+     * user is removed, so either all of them go or none do.
+     */
+    pub fn delete_users(&mut self, names: &[String]) -> Result<usize, String> {
+        if names.iter().any(|name| name == DEFAULT_USER) {
+            return Err("The 'default' user cannot be removed".to_string());
+        }
+        Ok(names
+            .iter()
+            .filter(|name| self.users.remove(name.as_str()).is_some())
+            .count())
+    }
+
+    /**
+     * Read users from an ACL file, one `user <name> <rule> ...` line per user.
+     * A user in the file replaces any existing user of that name, the
+     * `default` user included. A missing file adds no users.
+     */
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut loaded = BTreeSet::new();
+        for (number, line) in contents.lines().enumerate() {
+            let invalid = |reason: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), number + 1, reason),
+                )
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("user"), Some(name)) => {
+                    if !loaded.insert(name) {
+                        return Err(invalid(format!("duplicate user '{}'", name)));
+                    }
+                    let mut user = User::new(name);
+                    for rule in words {
+                        user.apply(rule).map_err(invalid)?;
+                    }
+                    self.users.insert(name.to_string(), user);
+                }
+                _ => return Err(invalid("expected 'user <name> <rules...>'".to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Write all users to an ACL file that `load` reads back
+     */
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut contents = String::new();
+        for user in self.users.values() {
+            contents.push_str(&format!("user {} {}\n", user.name, user.describe()));
+        }
+        snapshot::write_atomically(path, contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{args, TempFile};
+
+    #[test]
+    fn default_line_replaces_the_builtin_default_user() {
+        let file = TempFile::new("default", b"user default on >s3cret ~app:* +get\n");
+        let mut acl = Acl::new(&PasswordHash::new(b"password").to_string());
+        acl.load(file.path()).unwrap();
+
+        assert!(acl.authenticate(DEFAULT_USER, b"s3cret"));
+        assert!(!acl.authenticate(DEFAULT_USER, b"password"));
+        let user = acl.user(DEFAULT_USER).unwrap();
+        assert!(user.check("get", &args(&["GET", "app:1"])).is_ok());
+        assert!(user.check("set", &args(&["SET", "app:1", "v"])).is_err());
+        assert!(user.check("get", &args(&["GET", "other"])).is_err());
+    }
+
+    #[test]
+    fn builtin_default_user_stays_without_a_default_line() {
+        let file = TempFile::new("other", b"user alice on >wonderland ~* +@read\n");
+        let mut acl = Acl::new(&PasswordHash::new(b"password").to_string());
+        acl.load(file.path()).unwrap();
+
+        assert!(acl.authenticate(DEFAULT_USER, b"password"));
+        assert!(acl.authenticate("alice", b"wonderland"));
+        assert!(!acl.authenticate("alice", b"password"));
+    }
+
+    #[test]
+    fn rejects_duplicate_and_invalid_lines() {
+        let acl = || Acl::new("");
+        let duplicate = TempFile::new("duplicate", b"user a on nopass\nuser a off\n");
+        assert!(acl().load(duplicate.path()).is_err());
+        let invalid = TempFile::new("invalid", b"user a on +nosuchcommand\n");
+        assert!(acl().load(invalid.path()).is_err());
+        let garbage = TempFile::new("garbage", b"group a\n");
+        assert!(acl().load(garbage.path()).is_err());
+    }
+
+    #[test]
+    fn saved_file_loads_back() {
+        let mut acl = Acl::new(&PasswordHash::new(b"password").to_string());
+        acl.set_user("bob", &["on", ">builder", "~jobs:*", "+@write"])
+            .unwrap();
+        let file = TempFile::new("saved", b"");
+        acl.save(file.path()).unwrap();
+
+        let mut loaded = Acl::new("");
+        loaded.load(file.path()).unwrap();
+        assert!(loaded.authenticate(DEFAULT_USER, b"password"));
+        assert!(loaded.authenticate("bob", b"builder"));
+        let bob = loaded.user("bob").unwrap();
+        assert!(bob.check("set", &args(&["SET", "jobs:1", "v"])).is_ok());
+        assert!(bob.check("get", &args(&["GET", "jobs:1"])).is_err());
+    }
+
+    #[test]
+    fn authenticate_checks_every_password_and_the_enabled_flag() {
+        let mut acl = Acl::new("");
+        acl.set_user("carol", &["on", ">first", ">second"]).unwrap();
+        assert!(acl.authenticate("carol", b"first"));
+        assert!(acl.authenticate("carol", b"second"));
+        assert!(!acl.authenticate("carol", b"third"));
+
+        acl.set_user("carol", &["off"]).unwrap();
+        assert!(!acl.authenticate("carol", b"first"));
+        assert!(!acl.authenticate("nobody", b"first"));
+        assert!(!acl.authenticate("nobody", b""));
+    }
+
+    #[test]
+    fn deleting_users_is_all_or_nothing() {
+        let mut acl = Acl::new("");
+        acl.set_user("alice", &["on"]).unwrap();
+        acl.set_user("bob", &["on"]).unwrap();
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(acl.delete_users(&names(&["alice", "default"])).is_err());
+        assert!(acl.user("alice").is_some());
+
+        assert_eq!(acl.delete_users(&names(&["alice", "nobody", "bob"])), Ok(2));
+        assert!(acl.user("alice").is_none());
+        assert!(acl.user("bob").is_none());
+        assert!(acl.user("default").is_some());
+    }
+}
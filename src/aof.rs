@@ -0,0 +1,712 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::{str, thread};
+
+use crate::config::{FsyncPolicy, LogLevel};
+use crate::logger::log;
+use crate::resp::{self, Limits, Protocol, Reply};
+use crate::store::{self, Store};
+use crate::CacheEntry;
+
+/**
+ * Append-only log of every change made to the keyspace. Records are plain
+ * RESP arrays, in the same encoding clients use for requests:
+ *
+ * - `SET <key> <value>` or `SET <key> <value> PXAT <unix ms>`
+ * - `DEL <key> [<key> ...]`
+ * - `PEXPIREAT <key> <unix ms>`
+ * - `PERSIST <key>`
+ * - `APPEND <key> <value>`
+ * - `SETRANGE <key> <offset> <value>`
+ *
+ * Relative expiry times are always logged as absolute ones, so replaying the
+ * log later doesn't extend the lifetime of a key.
+ */
+pub struct Aof {
+    file: Arc<Mutex<Option<AofFile>>>,
+    fsync: AtomicU8,
+    dirty: AtomicBool,
+}
+
+struct AofFile {
+    file: File,
+    path: PathBuf,
+    /// Current size of the file in bytes
+    size: u64,
+    /// Size after the last rewrite (or at startup), the base for the growth ratio
+    base_size: u64,
+    /// Set while a rewrite is running
+    rewrite: Option<Rewrite>,
+}
+
+/**
+ * A running rewrite. The keyspace is dumped one shard at a time, in index
+ * order, so only writes to the shard being dumped have to wait.
+ */
+struct Rewrite {
+    /// Number of shards dumped so far
+    dumped: usize,
+    /// Records logged for shards that were already dumped. They are appended
+    /// to the rewritten file before it replaces the current one. Changes to
+    /// the other shards end up in the dump itself.
+    buffer: Vec<u8>,
+}
+
+impl Rewrite {
+    fn buffer(&mut self, args: &[&[u8]]) {
+        let dumped = |key: &&[u8]| store::shard_index(key) < self.dumped;
+        // Every record has its key first, DEL may have more keys in other shards
+        if args[0] == b"DEL" {
+            let mut del: Vec<&[u8]> = vec![b"DEL"];
+            del.extend(args[1..].iter().copied().filter(dumped));
+            if del.len() > 1 {
+                encode_record(&del, &mut self.buffer);
+            }
+        } else if dumped(&args[1]) {
+            encode_record(args, &mut self.buffer);
+        }
+    }
+}
+
+fn encode_record(args: &[&[u8]], out: &mut Vec<u8>) {
+    Reply::Array(args.iter().map(|arg| Reply::Bulk(arg.to_vec())).collect())
+        .encode(Protocol::Resp2, out);
+}
+
+/**
+ * Pass the record that sets `key` to `entry` to `emit`
+ */
+fn set_record<R>(key: &[u8], entry: &CacheEntry, emit: impl FnOnce(&[&[u8]]) -> R) -> R {
+    match entry.expires_at {
+        Some(expiration) => {
+            let at = store::to_unix_millis(expiration).to_string();
+            emit(&[b"SET", key, &entry.value, b"PXAT", at.as_bytes()])
+        }
+        None => emit(&[b"SET", key, &entry.value]),
+    }
+}
+
+impl Aof {
+    /**
+     * An AOF that drops every record, used when `appendonly` is off.
+     */
+    pub fn disabled() -> Aof {
+        Aof {
+            file: Arc::new(Mutex::new(None)),
+            fsync: AtomicU8::new(FsyncPolicy::No as u8),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn open(path: &Path, fsync: FsyncPolicy) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Aof {
+            file: Arc::new(Mutex::new(Some(AofFile {
+                file,
+                path: path.to_path_buf(),
+                size,
+                base_size: size,
+                rewrite: None,
+            }))),
+            fsync: AtomicU8::new(fsync as u8),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    pub fn set_fsync(&self, fsync: FsyncPolicy) {
+        self.fsync.store(fsync as u8, Ordering::Relaxed);
+    }
+
+    fn fsync_policy(&self) -> FsyncPolicy {
+        FsyncPolicy::from_u8(self.fsync.load(Ordering::Relaxed))
+    }
+
+    /**
+     * Log a change. Must be called while the write lock on the key's shard is
+     * held, so records end up in the same order the changes were applied.
+     *
+     * A failed write is cut back off the file, so a partial record doesn't end
+     * the log early. Under the `always` policy the error is returned and the
+     * caller must not apply the change; otherwise it is only logged.
+     */
+    pub fn append(&self, args: &[&[u8]]) -> io::Result<()> {
+        let mut aof = self.file.lock().unwrap();
+        let Some(aof) = aof.as_mut() else {
+            return Ok(());
+        };
+
+        let always = self.fsync_policy() == FsyncPolicy::Always;
+        let mut record = Vec::new();
+        encode_record(args, &mut record);
+        let written = aof.file.write_all(&record).and_then(|_| {
+            if always {
+                aof.file.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        match written {
+            Ok(()) => {
+                aof.size += record.len() as u64;
+                self.dirty.store(true, Ordering::Relaxed);
+            }
+            Err(e) => {
+                log(
+                    LogLevel::Warning,
+                    &format!("Error writing to the AOF file: {}", e),
+                );
+                let size = aof.size;
+                let truncated = aof
+                    .file
+                    .set_len(size)
+                    .and_then(|_| aof.file.seek(SeekFrom::Start(size)));
+                if let Err(e) = truncated {
+                    log(
+                        LogLevel::Warning,
+                        &format!("Error truncating the AOF file: {}", e),
+                    );
+                }
+                if always {
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(rewrite) = aof.rewrite.as_mut() {
+            rewrite.buffer(args);
+        }
+        Ok(())
+    }
+
+    /**
+     * Log that `key` was set to `entry`.
+     */
+    pub fn append_set(&self, key: &[u8], entry: &CacheEntry) -> io::Result<()> {
+        set_record(key, entry, |args| self.append(args))
+    }
+
+    /**
+     * Log that `key` now expires at `expires_at`.
+     */
+    pub fn append_expire(&self, key: &[u8], expires_at: Instant) -> io::Result<()> {
+        let at = store::to_unix_millis(expires_at).to_string();
+        self.append(&[b"PEXPIREAT", key, at.as_bytes()])
+    }
+
+    /**
+     * Log that `keys` were removed. Nothing is logged for an empty list.
+     */
+    pub fn append_del(&self, keys: &[Vec<u8>]) -> io::Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut args: Vec<&[u8]> = vec![b"DEL"];
+        args.extend(keys.iter().map(Vec::as_slice));
+        self.append(&args)
+    }
+
+    /**
+     * Flush written records to disk if the `everysec` policy asks for it.
+     * The sync runs on a cloned handle so appends are not blocked meanwhile.
+     */
+    pub fn fsync_if_due(&self) {
+        if self.fsync_policy() != FsyncPolicy::Everysec
+            || !self.dirty.swap(false, Ordering::Relaxed)
+        {
+            return;
+        }
+
+        let handle = match self.file.lock().unwrap().as_ref() {
+            Some(aof) => aof.file.try_clone(),
+            None => return,
+        };
+        if let Err(e) = handle.and_then(|file| file.sync_data()) {
+            log(
+                LogLevel::Warning,
+                &format!("Error syncing the AOF file: {}", e),
+            );
+        }
+    }
+
+    /**
+     * Flush everything written so far to disk, whatever the fsync policy
+     */
+    pub fn sync(&self) -> io::Result<()> {
+        match self.file.lock().unwrap().as_ref() {
+            Some(aof) => aof.file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Whether the file grew by more than `percentage` percent since the last
+     * rewrite and is at least `min_size` bytes large. A `percentage` of 0
+     * disables automatic rewrites.
+     */
+    pub fn rewrite_due(&self, percentage: u64, min_size: u64) -> bool {
+        let aof = self.file.lock().unwrap();
+        let Some(aof) = aof.as_ref() else {
+            return false;
+        };
+        if percentage == 0 || aof.rewrite.is_some() || aof.size < min_size {
+            return false;
+        }
+
+        let base = aof.base_size.max(1);
+        aof.size.saturating_sub(base) * 100 / base >= percentage
+    }
+
+    /**
+     * Start rewriting the log as the minimal set of records that rebuilds
+     * `store`. Dumping the keyspace and writing the new file happen in a
+     * background thread; records logged meanwhile go to both the current file
+     * and, if their shard was already dumped, the rewrite buffer.
+     */
+    pub fn background_rewrite(&self, store: &Arc<Store>) -> Result<(), String> {
+        let path = {
+            let mut aof = self.file.lock().unwrap();
+            let Some(aof) = aof.as_mut() else {
+                return Err("Append only file is not enabled".to_string());
+            };
+            if aof.rewrite.is_some() {
+                return Err("Background append only file rewriting already in progress".to_string());
+            }
+            aof.rewrite = Some(Rewrite {
+                dumped: 0,
+                buffer: Vec::new(),
+            });
+            aof.path.clone()
+        };
+
+        let shared = Arc::clone(&self.file);
+        let store = Arc::clone(store);
+        log(
+            LogLevel::Notice,
+            "Background append only file rewriting started",
+        );
+
+        thread::spawn(move || {
+            let mut temp = path.clone().into_os_string();
+            temp.push(".rewrite");
+            let temp = PathBuf::from(temp);
+
+            let data = dump(&store, &shared);
+            match finish_rewrite(&shared, &path, &temp, &data) {
+                Ok(size) => log(
+                    LogLevel::Notice,
+                    &format!(
+                        "Background AOF rewrite finished successfully, new size {} bytes",
+                        size
+                    ),
+                ),
+                Err(e) => {
+                    if let Some(aof) = shared.lock().unwrap().as_mut() {
+                        aof.rewrite = None;
+                    }
+                    fs::remove_file(&temp).ok();
+                    log(
+                        LogLevel::Warning,
+                        &format!("Background AOF rewrite failed: {}", e),
+                    );
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/**
+ * Encode the keyspace for a rewrite, one shard at a time. A shard is marked
+ * as dumped while its read lock is still held, so each change to it is either
+ * part of the dump or buffered, never both.
+ */
+fn dump(store: &Store, shared: &Mutex<Option<AofFile>>) -> Vec<u8> {
+    let mut data = Vec::with_capacity(store.used_memory());
+    for index in 0..store::SHARDS {
+        let shard = store.read_shard(index);
+        let now = Instant::now();
+        for (key, entry) in shard.iter() {
+            if entry.expires_at.is_some_and(|expiration| expiration <= now) {
+                continue;
+            }
+            set_record(key, entry, |args| encode_record(args, &mut data));
+        }
+
+        if let Some(rewrite) = shared
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|aof| aof.rewrite.as_mut())
+        {
+            rewrite.dumped = index + 1;
+        }
+    }
+    data
+}
+
+/**
+ * Write the rewritten log to `temp`, then, with appends paused, add the
+ * records buffered during the rewrite and move it over the current file.
+ * Returns the size of the new file.
+ */
+fn finish_rewrite(
+    shared: &Mutex<Option<AofFile>>,
+    path: &Path,
+    temp: &Path,
+    data: &[u8],
+) -> io::Result<u64> {
+    fs::remove_file(temp).ok();
+    let mut file = OpenOptions::new().create(true).append(true).open(temp)?;
+    file.write_all(data)?;
+    file.sync_data()?;
+
+    let mut aof = shared.lock().unwrap();
+    let aof = aof
+        .as_mut()
+        .ok_or_else(|| io::Error::other("append only file was closed"))?;
+
+    let buffer = aof
+        .rewrite
+        .take()
+        .map(|rewrite| rewrite.buffer)
+        .unwrap_or_default();
+    file.write_all(&buffer)?;
+    file.sync_data()?;
+    fs::rename(temp, path)?;
+
+    let size = (data.len() + buffer.len()) as u64;
+    aof.file = file;
+    aof.size = size;
+    aof.base_size = size;
+    Ok(size)
+}
+
+fn arg_str(arg: &[u8]) -> Result<&str, String> {
+    str::from_utf8(arg).map_err(|_| "invalid argument encoding".to_string())
+}
+
+fn arg_millis(arg: &[u8]) -> Result<u64, String> {
+    arg_str(arg)?
+        .parse::<u64>()
+        .map_err(|_| "invalid expiry time".to_string())
+}
+
+/**
+ * Apply one logged record to the store.
+ */
+fn apply(store: &mut Store, args: &[Vec<u8>], now_millis: u64) -> Result<(), String> {
+    let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+    match command.as_str() {
+        "SET" if args.len() == 3 || args.len() == 5 => {
+            let key = args[1].clone();
+            let expires_at = if args.len() == 5 {
+                if !args[3].eq_ignore_ascii_case(b"PXAT") {
+                    return Err("unknown SET option".to_string());
+                }
+                let at = arg_millis(&args[4])?;
+                if at <= now_millis {
+                    store.shard_mut(&key).remove(&key);
+                    return Ok(());
+                }
+                Some(store::from_unix_millis(at))
+            } else {
+                None
+            };
+
+            store.shard_mut(&key).insert(
+                key,
+                CacheEntry {
+                    expires_at,
+                    value: args[2].clone(),
+                },
+            );
+            Ok(())
+        }
+        "DEL" if args.len() >= 2 => {
+            for key in &args[1..] {
+                store.shard_mut(key).remove(key);
+            }
+            Ok(())
+        }
+        "PEXPIREAT" if args.len() == 3 => {
+            let key = &args[1];
+            let at = arg_millis(&args[2])?;
+            let shard = store.shard_mut(key);
+            if at <= now_millis {
+                shard.remove(key);
+            } else {
+                shard.set_expires_at(key, Some(store::from_unix_millis(at)));
+            }
+            Ok(())
+        }
+        "PERSIST" if args.len() == 2 => {
+            let key = &args[1];
+            store.shard_mut(key).set_expires_at(key, None);
+            Ok(())
+        }
+        "APPEND" if args.len() == 3 => {
+            let key = &args[1];
+            store.shard_mut(key).append(key, &args[2]);
+            Ok(())
+        }
+        "SETRANGE" if args.len() == 4 => {
+            let key = &args[1];
+            let offset = arg_str(&args[2])?
+                .parse::<usize>()
+                .map_err(|_| "invalid offset".to_string())?;
+            store.shard_mut(key).set_range(key, offset, &args[3]);
+            Ok(())
+        }
+        _ => Err(format!("unexpected command '{}'", command)),
+    }
+}
+
+/**
+ * Rebuild the store from the log at `path`. A missing file yields an empty
+ * store. If the last record is incomplete (the server died while writing it)
+ * and `load_truncated` is set, the torn record is cut off the file and
+ * loading continues; otherwise this is an error.
+ */
+pub fn load(path: &Path, load_truncated: bool) -> io::Result<Store> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Store::new()),
+        Err(e) => return Err(e),
+    };
+
+    let invalid = |pos: usize, reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bad file format at offset {}: {}", pos, reason),
+        )
+    };
+
+    let now_millis = store::to_unix_millis(Instant::now());
+    let mut store = Store::new();
+    let mut records = 0;
+    let mut pos = 0;
+
+    while pos < data.len() {
+        if data[pos] != b'*' {
+            return Err(invalid(pos, "expected a RESP array".to_string()));
+        }
+        match resp::parse_request(&data[pos..], &Limits::NONE) {
+            Ok(Some((args, consumed))) if !args.is_empty() => {
+                apply(&mut store, &args, now_millis).map_err(|e| invalid(pos, e))?;
+                records += 1;
+                pos += consumed;
+            }
+            Ok(None) => break,
+            Ok(Some(_)) => return Err(invalid(pos, "empty record".to_string())),
+            Err(e) => return Err(invalid(pos, e.to_string())),
+        }
+    }
+
+    if pos < data.len() {
+        if !load_truncated {
+            return Err(invalid(
+                pos,
+                "unexpected end of file (set aof-load-truncated yes to load it anyway)".to_string(),
+            ));
+        }
+        log(
+            LogLevel::Warning,
+            &format!(
+                "AOF file ends with an incomplete record, truncating it from {} to {} bytes",
+                data.len(),
+                pos
+            ),
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(pos as u64)?;
+    }
+
+    log(
+        LogLevel::Notice,
+        &format!(
+            "DB loaded from append only file: {} records, {} keys",
+            records,
+            store.read_all().len()
+        ),
+    );
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::TempFile;
+
+    fn records(records: &[&[&[u8]]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for args in records {
+            encode_record(args, &mut data);
+        }
+        data
+    }
+
+    fn value(store: &Store, key: &[u8]) -> Option<Vec<u8>> {
+        store.read(key).get(key).map(|entry| entry.value.clone())
+    }
+
+    #[test]
+    fn replays_every_record_type() {
+        let later = (store::to_unix_millis(Instant::now()) + 3_600_000).to_string();
+        let data = records(&[
+            &[b"SET", b"a", b"1"],
+            &[b"SET", b"b", b"2", b"PXAT", later.as_bytes()],
+            &[b"SET", b"c", b"3", b"PXAT", b"1"],
+            &[b"SET", b"d", b"4"],
+            &[b"DEL", b"d", b"missing"],
+            &[b"PEXPIREAT", b"a", later.as_bytes()],
+            &[b"PERSIST", b"b"],
+            &[b"APPEND", b"e", b"hello"],
+            &[b"APPEND", b"e", b" world"],
+            &[b"SETRANGE", b"e", b"6", b"there"],
+            &[b"SETRANGE", b"f", b"2", b"x"],
+        ]);
+        let file = TempFile::new("replay", &data);
+
+        let store = load(file.path(), false).unwrap();
+        assert_eq!(store.read_all().len(), 4);
+        assert!(store.read(b"a").get(b"a").unwrap().expires_at.is_some());
+        assert_eq!(store.read(b"b").get(b"b").unwrap().expires_at, None);
+        assert_eq!(value(&store, b"c"), None);
+        assert_eq!(value(&store, b"d"), None);
+        assert_eq!(value(&store, b"e"), Some(b"hello there".to_vec()));
+        assert_eq!(value(&store, b"f"), Some(b"\0\0x".to_vec()));
+    }
+
+    #[test]
+    fn past_expiry_removes_the_key() {
+        let data = records(&[&[b"SET", b"a", b"1"], &[b"PEXPIREAT", b"a", b"1"]]);
+        let file = TempFile::new("past-expiry", &data);
+        assert_eq!(load(file.path(), false).unwrap().read_all().len(), 0);
+    }
+
+    #[test]
+    fn loads_what_the_log_wrote() {
+        let file = TempFile::new("round-trip", b"");
+        let aof = Aof::open(file.path(), FsyncPolicy::No).unwrap();
+        let expires_at = Instant::now() + Duration::from_secs(60);
+        let entry = CacheEntry {
+            expires_at: Some(expires_at),
+            value: b"\xff\r\n".to_vec(),
+        };
+        aof.append_set(b"\x00key", &entry).unwrap();
+        aof.append_set(
+            b"gone",
+            &CacheEntry {
+                expires_at: None,
+                value: b"v".to_vec(),
+            },
+        )
+        .unwrap();
+        aof.append_del(&[b"gone".to_vec()]).unwrap();
+        aof.append_del(&[]).unwrap();
+        aof.append_expire(b"\x00key", expires_at + Duration::from_secs(60))
+            .unwrap();
+        drop(aof);
+
+        let store = load(file.path(), false).unwrap();
+        assert_eq!(store.read_all().len(), 1);
+        let shard = store.read(b"\x00key");
+        let loaded = shard.get(b"\x00key").unwrap();
+        assert_eq!(loaded.value, b"\xff\r\n");
+        assert!(loaded.expires_at.unwrap() > expires_at + Duration::from_secs(59));
+    }
+
+    #[test]
+    fn failed_writes_are_returned_only_under_always() {
+        // Every write to /dev/full fails with "No space left on device"
+        let aof = Aof::open(Path::new("/dev/full"), FsyncPolicy::Always).unwrap();
+        assert!(aof.append(&[b"DEL", b"k"]).is_err());
+
+        aof.set_fsync(FsyncPolicy::Everysec);
+        assert!(aof.append(&[b"DEL", b"k"]).is_ok());
+    }
+
+    /**
+     * A key that lives in shard `index`
+     */
+    fn key_in_shard(index: usize) -> Vec<u8> {
+        (0..)
+            .map(|n: u32| format!("key{}", n).into_bytes())
+            .find(|key| store::shard_index(key) == index)
+            .unwrap()
+    }
+
+    #[test]
+    fn rewrite_buffers_only_changes_to_dumped_shards() {
+        let dumped = key_in_shard(0);
+        let pending = key_in_shard(1);
+        let mut rewrite = Rewrite {
+            dumped: 1,
+            buffer: Vec::new(),
+        };
+        rewrite.buffer(&[b"APPEND", &dumped, b"x"]);
+        rewrite.buffer(&[b"APPEND", &pending, b"y"]);
+        rewrite.buffer(&[b"DEL", &pending, &dumped]);
+        rewrite.buffer(&[b"DEL", &pending]);
+
+        assert_eq!(
+            rewrite.buffer,
+            records(&[&[b"APPEND", &dumped, b"x"], &[b"DEL", &dumped]])
+        );
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_store() {
+        let path = std::env::temp_dir().join("keyvy-aof-does-not-exist.aof");
+        assert_eq!(load(&path, false).unwrap().read_all().len(), 0);
+    }
+
+    #[test]
+    fn torn_final_record_is_an_error_unless_allowed() {
+        let complete = records(&[&[b"SET", b"a", b"1"]]);
+        let mut data = complete.clone();
+        data.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$5\r\nab");
+        let file = TempFile::new("torn", &data);
+
+        assert!(load(file.path(), false).is_err());
+        assert_eq!(fs::read(file.path()).unwrap(), data);
+
+        let store = load(file.path(), true).unwrap();
+        assert_eq!(value(&store, b"a"), Some(b"1".to_vec()));
+        assert_eq!(value(&store, b"b"), None);
+        assert_eq!(fs::read(file.path()).unwrap(), complete);
+    }
+
+    #[test]
+    fn damaged_records_are_always_an_error() {
+        let valid = records(&[&[b"SET", b"a", b"1"]]);
+        let mut inline = valid.clone();
+        inline.extend_from_slice(b"SET b 2\r\n");
+        let mut bad_length = valid.clone();
+        bad_length.extend_from_slice(b"*1\r\n$x\r\n");
+
+        for (name, data) in [
+            ("inline", inline),
+            ("bad-length", bad_length),
+            ("empty", b"*0\r\n".to_vec()),
+            ("unknown", records(&[&[b"FLUSHALL"]])),
+            ("wrong-arity", records(&[&[b"SET", b"a"]])),
+            ("bad-option", records(&[&[b"SET", b"a", b"1", b"EX", b"5"]])),
+            ("bad-time", records(&[&[b"PEXPIREAT", b"a", b"soon"]])),
+        ] {
+            let file = TempFile::new(name, &data);
+            assert!(load(file.path(), true).is_err(), "{}", name);
+            assert_eq!(fs::read(file.path()).unwrap(), data, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,970 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::password::PasswordHash;
+use crate::resp::Limits;
+
+/**
+ * Config file that is picked up from the working directory when no other
+ * file is given through `--config` or `KEYVY_CONFIG`.
+ */
+const DEFAULT_CONFIG_FILE: &str = "keyvy.conf";
+
+/**
+ * Prefix of the environment variables that override config file settings,
+ * e.g. `KEYVY_PORT` or `KEYVY_MAXMEMORY`.
+ */
+const ENV_PREFIX: &str = "KEYVY_";
+
+/**
+ * Names of all settings, in the order they are listed and written out.
+ */
+pub const PARAMETERS: &[&str] = &[
+    "bind",
+    "protected-mode",
+    "port",
+    "unixsocket",
+    "unixsocketperm",
+    "tls-port",
+    "tls-cert-file",
+    "tls-key-file",
+    "tls-ca-cert-file",
+    "tls-auth-clients",
+    "io-threads",
+    "maxclients",
+    "timeout",
+    "tcp-keepalive",
+    "proto-max-line-len",
+    "proto-max-bulk-len",
+    "proto-max-args",
+    "requirepass",
+    "aclfile",
+    "hz",
+    "maxmemory",
+    "loglevel",
+    "dir",
+    "dbfilename",
+    "appendonly",
+    "appendfilename",
+    "appendfsync",
+    "aof-load-truncated",
+    "auto-aof-rewrite-percentage",
+    "auto-aof-rewrite-min-size",
+];
+
+/**
+ * Settings that only take effect at startup and can't be changed with CONFIG SET.
+ */
+pub const IMMUTABLE: &[&str] = &[
+    "bind",
+    "port",
+    "unixsocket",
+    "unixsocketperm",
+    "tls-port",
+    "io-threads",
+    "aclfile",
+    "appendonly",
+    "appendfilename",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+impl LogLevel {
+    fn parse(value: &str) -> Option<LogLevel> {
+        match value.to_lowercase().as_str() {
+            "debug" => Some(LogLevel::Debug),
+            "verbose" => Some(LogLevel::Verbose),
+            "notice" => Some(LogLevel::Notice),
+            "warning" => Some(LogLevel::Warning),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Verbose => "verbose",
+            LogLevel::Notice => "notice",
+            LogLevel::Warning => "warning",
+        }
+    }
+}
+
+/**
+ * When the append-only file is flushed to disk
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FsyncPolicy {
+    /// After every write
+    Always,
+    /// Once per second from a background thread
+    Everysec,
+    /// Whenever the operating system decides to
+    No,
+}
+
+impl FsyncPolicy {
+    fn parse(value: &str) -> Option<FsyncPolicy> {
+        match value.to_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::Everysec),
+            "no" => Some(FsyncPolicy::No),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::Everysec => "everysec",
+            FsyncPolicy::No => "no",
+        }
+    }
+
+    pub fn from_u8(value: u8) -> FsyncPolicy {
+        match value {
+            0 => FsyncPolicy::Always,
+            1 => FsyncPolicy::Everysec,
+            _ => FsyncPolicy::No,
+        }
+    }
+}
+
+/**
+ * Whether TLS clients must present a certificate
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TlsAuthClients {
+    /// Client certificates are neither requested nor checked
+    No,
+    /// Every client needs a certificate signed by `tls-ca-cert-file`
+    Yes,
+    /// A certificate is checked if the client sends one
+    Optional,
+}
+
+impl TlsAuthClients {
+    fn parse(value: &str) -> Option<TlsAuthClients> {
+        match value.to_lowercase().as_str() {
+            "no" => Some(TlsAuthClients::No),
+            "yes" => Some(TlsAuthClients::Yes),
+            "optional" => Some(TlsAuthClients::Optional),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TlsAuthClients::No => "no",
+            TlsAuthClients::Yes => "yes",
+            TlsAuthClients::Optional => "optional",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Addresses the TCP listeners bind to, each optionally with its own port
+    pub bind: Vec<String>,
+    /// Only accept clients from the loopback interface while the default
+    /// user has no password
+    pub protected_mode: bool,
+    /// Port of the TCP listener, `0` for none
+    pub port: u16,
+    /// Path of a Unix domain socket to listen on as well, empty for none
+    pub unixsocket: String,
+    /// Permissions of the Unix socket file, `0` to leave them to the umask
+    pub unixsocketperm: u32,
+    /// Port of the TLS listener, `0` for none
+    pub tls_port: u16,
+    /// Server certificate chain in PEM format
+    pub tls_cert_file: String,
+    /// Private key of the server certificate in PEM format
+    pub tls_key_file: String,
+    /// CA certificates client certificates are checked against, in PEM format
+    pub tls_ca_cert_file: String,
+    pub tls_auth_clients: TlsAuthClients,
+    /// Number of threads serving client connections
+    pub io_threads: usize,
+    /// Connections beyond this many are refused
+    pub maxclients: usize,
+    /// Seconds after which an idle connection is closed, `0` to keep it open
+    pub timeout: u64,
+    /// Seconds between TCP keepalive probes on idle connections, `0` to disable them
+    pub tcp_keepalive: u64,
+    /// Longest inline request or protocol header line a client may send
+    pub proto_max_line_len: usize,
+    /// Longest single argument a client may send
+    pub proto_max_bulk_len: usize,
+    /// Most arguments a client may send in one request
+    pub proto_max_args: usize,
+    /// Hash of the default user's password in `PasswordHash` form, empty to
+    /// log in without AUTH
+    pub requirepass: String,
+    /// File users are loaded from and saved to with ACL LOAD / ACL SAVE, empty for none
+    pub aclfile: String,
+    /// Active expire cycles per second
+    pub hz: u64,
+    /// Memory limit in bytes for the keyspace, `0` for no limit
+    pub maxmemory: usize,
+    pub loglevel: LogLevel,
+    /// Directory persistence files are written to
+    pub dir: PathBuf,
+    /// Name of the snapshot file inside `dir`
+    pub dbfilename: String,
+    /// Log every change to an append-only file
+    pub appendonly: bool,
+    /// Name of the append-only file inside `dir`
+    pub appendfilename: String,
+    pub appendfsync: FsyncPolicy,
+    /// Load an append-only file whose last record is incomplete by cutting that record off
+    pub aof_load_truncated: bool,
+    /// Rewrite the append-only file once it grew by this many percent, `0` to disable
+    pub auto_aof_rewrite_percentage: u64,
+    /// Don't rewrite the append-only file automatically while it is smaller than this
+    pub auto_aof_rewrite_min_size: usize,
+    /// File the settings were loaded from, if any
+    pub config_file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: vec!["127.0.0.1".to_string()],
+            protected_mode: true,
+            port: 7878,
+            unixsocket: String::new(),
+            unixsocketperm: 0,
+            tls_port: 0,
+            tls_cert_file: String::new(),
+            tls_key_file: String::new(),
+            tls_ca_cert_file: String::new(),
+            tls_auth_clients: TlsAuthClients::No,
+            io_threads: 4,
+            maxclients: 10000,
+            timeout: 0,
+            tcp_keepalive: 300,
+            proto_max_line_len: 64 * 1024,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            proto_max_args: 1024 * 1024,
+            requirepass: String::new(),
+            aclfile: String::new(),
+            hz: 10,
+            maxmemory: 0,
+            loglevel: LogLevel::Notice,
+            dir: PathBuf::from("."),
+            dbfilename: "dump.kvy".to_string(),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: FsyncPolicy::Everysec,
+            aof_load_truncated: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            config_file: None,
+        }
+    }
+}
+
+/**
+ * Parse a memory amount such as `1048576`, `512kb`, `100mb` or `1gb`.
+ */
+fn parse_memory(name: &str, value: &str) -> Result<usize, String> {
+    let lower = value.to_lowercase();
+    let (digits, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => lower.split_at(i),
+        None => (lower.as_str(), ""),
+    };
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid value for '{}': '{}'", name, value)),
+    };
+    parse_number::<usize>(name, digits)?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Invalid value for '{}': '{}' is too large", name, value))
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!(
+            "Invalid value for '{}': '{}' (expected yes or no)",
+            name, value
+        )),
+    }
+}
+
+fn format_bool(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+/**
+ * A file name that can safely be joined to `dir`.
+ */
+fn parse_file_name(name: &str, value: &str) -> Result<String, String> {
+    if value.is_empty() || value.contains(std::path::is_separator) {
+        return Err(format!(
+            "Invalid value for '{}': '{}' must be a plain file name",
+            name, value
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/**
+ * Split a `bind` address into host and port: `10.0.0.1`, `::1`, `localhost`,
+ * or with a port `10.0.0.1:7000`, `[::1]:7000`, `localhost:7000`.
+ */
+pub fn split_port(address: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':')?)),
+        }
+    } else if address.matches(':').count() == 1 {
+        let (host, port) = address.split_once(':')?;
+        (host, Some(port))
+    } else {
+        // No port, or a bare IPv6 address
+        (address, None)
+    };
+    if host.is_empty() {
+        return None;
+    }
+    match port {
+        Some(port) => Some((host, Some(port.parse().ok()?))),
+        None => Some((host, None)),
+    }
+}
+
+/**
+ * Parse a size limit, which takes the same units as `maxmemory` but can't be 0
+ */
+fn parse_limit(name: &str, value: &str) -> Result<usize, String> {
+    match parse_memory(name, value)? {
+        0 => Err(format!("Invalid value for '{}': must be positive", name)),
+        limit => Ok(limit),
+    }
+}
+
+/**
+ * Largest `proto-max-bulk-len`. Keys and values can't grow beyond it, and
+ * snapshots store their lengths in 32 bits.
+ */
+const MAX_BULK_LEN: usize = u32::MAX as usize;
+
+fn parse_bulk_len(name: &str, value: &str) -> Result<usize, String> {
+    match parse_limit(name, value)? {
+        limit if limit > MAX_BULK_LEN => Err(format!(
+            "Invalid value for '{}': must be at most {}",
+            name, MAX_BULK_LEN
+        )),
+        limit => Ok(limit),
+    }
+}
+
+/**
+ * A password is only kept as a salted hash. A value starting with `#` already
+ * is one, anything else is hashed here.
+ */
+fn parse_password(name: &str, value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        Ok(String::new())
+    } else if value.starts_with('#') {
+        PasswordHash::parse(value)
+            .map(|hash| hash.to_string())
+            .ok_or_else(|| format!("Invalid value for '{}': malformed password hash", name))
+    } else {
+        Ok(PasswordHash::new(value.as_bytes()).to_string())
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value for '{}': '{}'", name, value))
+}
+
+impl Config {
+    /**
+     * Change a single setting by name, validating the new value.
+     */
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name.to_lowercase().as_str() {
+            "bind" => {
+                let addresses: Vec<String> = value.split_whitespace().map(str::to_string).collect();
+                if addresses.is_empty() {
+                    return Err("Invalid value for 'bind': address can't be empty".to_string());
+                }
+                if let Some(invalid) = addresses.iter().find(|address| split_port(address).is_none()) {
+                    return Err(format!("Invalid value for 'bind': '{}'", invalid));
+                }
+                self.bind = addresses;
+            }
+            "protected-mode" => self.protected_mode = parse_bool(name, value)?,
+            "port" => self.port = parse_number(name, value)?,
+            "unixsocket" => self.unixsocket = value.to_string(),
+            "unixsocketperm" => {
+                self.unixsocketperm = u32::from_str_radix(value, 8)
+                    .ok()
+                    .filter(|perm| *perm <= 0o777)
+                    .ok_or_else(|| {
+                        format!(
+                            "Invalid value for 'unixsocketperm': '{}' (expected octal permissions such as 700)",
+                            value
+                        )
+                    })?
+            }
+            "tls-port" => self.tls_port = parse_number(name, value)?,
+            "tls-cert-file" => self.tls_cert_file = value.to_string(),
+            "tls-key-file" => self.tls_key_file = value.to_string(),
+            "tls-ca-cert-file" => self.tls_ca_cert_file = value.to_string(),
+            "tls-auth-clients" => {
+                self.tls_auth_clients = TlsAuthClients::parse(value).ok_or_else(|| {
+                    format!(
+                        "Invalid value for 'tls-auth-clients': '{}' (expected yes, no or optional)",
+                        value
+                    )
+                })?
+            }
+            "io-threads" => {
+                let threads = parse_number(name, value)?;
+                if !(1..=128).contains(&threads) {
+                    return Err("Invalid value for 'io-threads': must be between 1 and 128".to_string());
+                }
+                self.io_threads = threads;
+            }
+            "maxclients" => {
+                let maxclients = parse_number(name, value)?;
+                if maxclients == 0 {
+                    return Err("Invalid value for 'maxclients': must be positive".to_string());
+                }
+                self.maxclients = maxclients;
+            }
+            "timeout" => self.timeout = parse_number(name, value)?,
+            "tcp-keepalive" => self.tcp_keepalive = parse_number(name, value)?,
+            "proto-max-line-len" => self.proto_max_line_len = parse_limit(name, value)?,
+            "proto-max-bulk-len" => self.proto_max_bulk_len = parse_bulk_len(name, value)?,
+            "proto-max-args" => {
+                let args = parse_number(name, value)?;
+                if args == 0 {
+                    return Err("Invalid value for 'proto-max-args': must be positive".to_string());
+                }
+                self.proto_max_args = args;
+            }
+            "requirepass" => self.requirepass = parse_password(name, value)?,
+            "aclfile" => self.aclfile = value.to_string(),
+            "hz" => {
+                let hz = parse_number(name, value)?;
+                if !(1..=500).contains(&hz) {
+                    return Err("Invalid value for 'hz': must be between 1 and 500".to_string());
+                }
+                self.hz = hz;
+            }
+            "maxmemory" => self.maxmemory = parse_memory(name, value)?,
+            "loglevel" => {
+                self.loglevel = LogLevel::parse(value).ok_or_else(|| {
+                    format!(
+                        "Invalid value for 'loglevel': '{}' (expected debug, verbose, notice or warning)",
+                        value
+                    )
+                })?
+            }
+            "dir" => {
+                if !Path::new(value).is_dir() {
+                    return Err(format!("Invalid value for 'dir': '{}' is not a directory", value));
+                }
+                self.dir = PathBuf::from(value);
+            }
+            "dbfilename" => self.dbfilename = parse_file_name(name, value)?,
+            "appendonly" => self.appendonly = parse_bool(name, value)?,
+            "appendfilename" => self.appendfilename = parse_file_name(name, value)?,
+            "appendfsync" => {
+                self.appendfsync = FsyncPolicy::parse(value).ok_or_else(|| {
+                    format!(
+                        "Invalid value for 'appendfsync': '{}' (expected always, everysec or no)",
+                        value
+                    )
+                })?
+            }
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(name, value)?,
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage = parse_number(name, value)?
+            }
+            "auto-aof-rewrite-min-size" => {
+                self.auto_aof_rewrite_min_size = parse_memory(name, value)?
+            }
+            _ => return Err(format!("Unknown setting '{}'", name)),
+        }
+        Ok(())
+    }
+
+    /**
+     * Current value of a setting, formatted the way it is written in a config file.
+     */
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name.to_lowercase().as_str() {
+            "bind" => self.bind.join(" "),
+            "protected-mode" => format_bool(self.protected_mode),
+            "port" => self.port.to_string(),
+            "unixsocket" => self.unixsocket.clone(),
+            "unixsocketperm" => format!("{:o}", self.unixsocketperm),
+            "tls-port" => self.tls_port.to_string(),
+            "tls-cert-file" => self.tls_cert_file.clone(),
+            "tls-key-file" => self.tls_key_file.clone(),
+            "tls-ca-cert-file" => self.tls_ca_cert_file.clone(),
+            "tls-auth-clients" => self.tls_auth_clients.name().to_string(),
+            "io-threads" => self.io_threads.to_string(),
+            "maxclients" => self.maxclients.to_string(),
+            "timeout" => self.timeout.to_string(),
+            "tcp-keepalive" => self.tcp_keepalive.to_string(),
+            "proto-max-line-len" => self.proto_max_line_len.to_string(),
+            "proto-max-bulk-len" => self.proto_max_bulk_len.to_string(),
+            "proto-max-args" => self.proto_max_args.to_string(),
+            "requirepass" => self.requirepass.clone(),
+            "aclfile" => self.aclfile.clone(),
+            "hz" => self.hz.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "loglevel" => self.loglevel.name().to_string(),
+            "dir" => self.dir.display().to_string(),
+            "dbfilename" => self.dbfilename.clone(),
+            "appendonly" => format_bool(self.appendonly),
+            "appendfilename" => self.appendfilename.clone(),
+            "appendfsync" => self.appendfsync.name().to_string(),
+            "aof-load-truncated" => format_bool(self.aof_load_truncated),
+            "auto-aof-rewrite-percentage" => self.auto_aof_rewrite_percentage.to_string(),
+            "auto-aof-rewrite-min-size" => self.auto_aof_rewrite_min_size.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /**
+     * Size limits for client requests
+     */
+    pub fn request_limits(&self) -> Limits {
+        Limits {
+            max_line: self.proto_max_line_len,
+            max_bulk: self.proto_max_bulk_len,
+            max_args: self.proto_max_args,
+        }
+    }
+
+    /**
+     * Location of the ACL file, if one is configured
+     */
+    pub fn acl_path(&self) -> Option<PathBuf> {
+        if self.aclfile.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(&self.aclfile))
+        }
+    }
+
+    /**
+     * Location of the snapshot file
+     */
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    /**
+     * Location of the append-only file
+     */
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+
+    /**
+     * Write the current settings back to the config file they were loaded from.
+     * Comments and the order of existing lines are kept, settings missing from
+     * the file are appended.
+     */
+    pub fn rewrite(&self) -> io::Result<()> {
+        let path = self.config_file.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "The server is running without a config file",
+            )
+        })?;
+
+        let existing = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut written = Vec::new();
+        let mut output = String::new();
+        for line in existing.lines() {
+            match parse_line(line) {
+                Some((name, _)) => {
+                    let name = name.to_lowercase();
+                    // Drop repeated and unknown settings, keep the first occurrence
+                    if written.contains(&name) || !PARAMETERS.contains(&name.as_str()) {
+                        continue;
+                    }
+                    output.push_str(&self.format_line(&name));
+                    written.push(name);
+                }
+                None => output.push_str(line),
+            }
+            output.push('\n');
+        }
+
+        let defaults = Config::default();
+        for name in PARAMETERS {
+            if !written.iter().any(|written| written == name)
+                && self.get(name) != defaults.get(name)
+            {
+                output.push_str(&self.format_line(name));
+                output.push('\n');
+            }
+        }
+
+        // Write to a temporary file first so a crash can't leave a half-written config
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, output)?;
+        fs::rename(&temp, path)
+    }
+
+    fn format_line(&self, name: &str) -> String {
+        let value = self.get(name).unwrap_or_default();
+        if value.is_empty() || value.contains(char::is_whitespace) || value.starts_with('"') {
+            format!("{} \"{}\"", name, value)
+        } else {
+            format!("{} {}", name, value)
+        }
+    }
+
+    /**
+     * Build the configuration from, in increasing order of precedence:
+     * built-in defaults, the config file, `KEYVY_*` environment variables
+     * and command-line flags.
+     */
+    pub fn load(args: &[String]) -> Result<Config, ConfigError> {
+        let flags = parse_flags(args)?;
+        let mut config = Config::default();
+
+        let explicit_file = flags
+            .iter()
+            .find(|(name, _)| name == "config")
+            .map(|(_, value)| PathBuf::from(value))
+            .or_else(|| std::env::var_os(format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from));
+
+        match explicit_file {
+            Some(path) => config.load_file(&path)?,
+            None => {
+                let path = PathBuf::from(DEFAULT_CONFIG_FILE);
+                if path.exists() {
+                    config.load_file(&path)?;
+                }
+            }
+        }
+
+        for name in PARAMETERS {
+            let var = format!("{}{}", ENV_PREFIX, name.to_uppercase().replace('-', "_"));
+            if let Ok(value) = std::env::var(&var) {
+                config
+                    .set(name, &value)
+                    .map_err(|e| ConfigError(format!("{} (from {})", e, var)))?;
+            }
+        }
+
+        for (name, value) in flags.iter().filter(|(name, _)| name != "config") {
+            config
+                .set(name, value)
+                .map_err(|e| ConfigError(format!("{} (from --{})", e, name)))?;
+        }
+
+        Ok(config)
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            ConfigError(format!(
+                "Can't read config file '{}': {}",
+                path.display(),
+                e
+            ))
+        })?;
+
+        for (number, line) in contents.lines().enumerate() {
+            let (name, value) = match parse_line(line) {
+                Some(setting) => setting,
+                None => continue,
+            };
+            self.set(name, &value).map_err(|e| {
+                ConfigError(format!("{} ({}, line {})", e, path.display(), number + 1))
+            })?;
+        }
+
+        self.config_file = Some(path.to_path_buf());
+        Ok(())
+    }
+}
+
+/**
+ * Split a config file line into setting name and value. Blank lines and
+ * `#` comments yield `None`. Values may be wrapped in double quotes.
+ */
+fn parse_line(line: &str) -> Option<(&str, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let (name, value) = match line.split_once(char::is_whitespace) {
+        Some((name, value)) => (name, value.trim()),
+        None => (line, ""),
+    };
+
+    let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        value[1..value.len() - 1].to_string()
+    } else {
+        value.to_string()
+    };
+
+    Some((name, value))
+}
+
+/**
+ * Collect `--name value` and `--name=value` flags from the command line.
+ */
+fn parse_flags(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .ok_or_else(|| ConfigError(format!("Unexpected argument '{}'", arg)))?;
+
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError(format!("Missing value for '--{}'", flag)))?;
+                (flag.to_string(), value.clone())
+            }
+        };
+
+        if name != "config" && !PARAMETERS.contains(&name.as_str()) {
+            return Err(ConfigError(format!("Unknown option '--{}'", name)));
+        }
+        flags.push((name, value));
+    }
+
+    Ok(flags)
+}
+
+pub fn usage() -> String {
+    let mut usage = String::from("Usage: keyvy [--config <file>]");
+    for name in PARAMETERS {
+        usage.push_str(&format!(" [--{} <value>]", name));
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempFile;
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_line_strips_quotes_and_skips_comments() {
+        assert_eq!(parse_line("port 7000"), Some(("port", "7000".to_string())));
+        assert_eq!(
+            parse_line("  dir   \"/var/lib/my keyvy\"  "),
+            Some(("dir", "/var/lib/my keyvy".to_string()))
+        );
+        assert_eq!(
+            parse_line("requirepass \"\""),
+            Some(("requirepass", String::new()))
+        );
+        assert_eq!(
+            parse_line("requirepass"),
+            Some(("requirepass", String::new()))
+        );
+        assert_eq!(
+            parse_line("dbfilename \"dump.kvy"),
+            Some(("dbfilename", "\"dump.kvy".to_string()))
+        );
+        assert_eq!(
+            parse_line("dbfilename \""),
+            Some(("dbfilename", "\"".to_string()))
+        );
+        assert_eq!(parse_line(""), None);
+        assert_eq!(parse_line("   "), None);
+        assert_eq!(parse_line("  # port 7000"), None);
+    }
+
+    #[test]
+    fn split_port_handles_ipv4_ipv6_and_names() {
+        assert_eq!(split_port("127.0.0.1"), Some(("127.0.0.1", None)));
+        assert_eq!(
+            split_port("127.0.0.1:6379"),
+            Some(("127.0.0.1", Some(6379)))
+        );
+        assert_eq!(
+            split_port("localhost:7000"),
+            Some(("localhost", Some(7000)))
+        );
+        assert_eq!(split_port("[::1]:6379"), Some(("::1", Some(6379))));
+        assert_eq!(split_port("[::1]"), Some(("::1", None)));
+        assert_eq!(split_port("::"), Some(("::", None)));
+        assert_eq!(split_port("::1"), Some(("::1", None)));
+
+        assert_eq!(split_port("127.0.0.1:"), None);
+        assert_eq!(split_port("127.0.0.1:65536"), None);
+        assert_eq!(split_port("127.0.0.1:port"), None);
+        assert_eq!(split_port("[::1]:"), None);
+        assert_eq!(split_port("[::1]6379"), None);
+        assert_eq!(split_port("[::1"), None);
+        assert_eq!(split_port(":6379"), None);
+        assert_eq!(split_port("[]:6379"), None);
+    }
+
+    #[test]
+    fn parse_flags_accepts_both_forms() {
+        let flags = parse_flags(&strings(&[
+            "--port", "7000", "--hz=20", "--config", "a.conf",
+        ]));
+        assert_eq!(
+            flags.unwrap(),
+            vec![
+                ("port".to_string(), "7000".to_string()),
+                ("hz".to_string(), "20".to_string()),
+                ("config".to_string(), "a.conf".to_string()),
+            ]
+        );
+        // Only the first `=` separates name and value
+        let flags = parse_flags(&strings(&["--requirepass=a=b"])).unwrap();
+        assert_eq!(flags, vec![("requirepass".to_string(), "a=b".to_string())]);
+    }
+
+    #[test]
+    fn parse_flags_rejects_unknown_and_incomplete_flags() {
+        for (args, error) in [
+            (
+                &["--nosuchoption", "1"][..],
+                "Unknown option '--nosuchoption'",
+            ),
+            (&["--nosuchoption=1"], "Unknown option '--nosuchoption'"),
+            (&["port", "7000"], "Unexpected argument 'port'"),
+            (&["--port"], "Missing value for '--port'"),
+        ] {
+            let e = parse_flags(&strings(args)).unwrap_err();
+            assert_eq!(e.to_string(), error, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn parse_memory_understands_units() {
+        for (value, bytes) in [
+            ("1048576", 1048576),
+            ("0", 0),
+            ("10b", 10),
+            ("1k", 1000),
+            ("512kb", 512 * 1024),
+            ("2m", 2_000_000),
+            ("100MB", 100 * 1024 * 1024),
+            ("1g", 1_000_000_000),
+            ("1Gb", 1024 * 1024 * 1024),
+        ] {
+            assert_eq!(parse_memory("maxmemory", value), Ok(bytes), "{}", value);
+        }
+        for value in ["", "mb", "1tb", "1 mb", "-1", "1.5gb"] {
+            assert!(parse_memory("maxmemory", value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_memory_rejects_overflow() {
+        let too_large = format!("{}gb", usize::MAX / 2);
+        let e = parse_memory("maxmemory", &too_large).unwrap_err();
+        assert!(e.contains("too large"), "{}", e);
+        assert!(parse_memory("maxmemory", "99999999999999999999999").is_err());
+    }
+
+    #[test]
+    fn flags_override_environment_overrides_file() {
+        let file = TempFile::new(
+            "precedence.conf",
+            b"hz 20\nmaxmemory 1mb\nloglevel warning\n",
+        );
+        // No other test reads these variables
+        std::env::set_var("KEYVY_HZ", "30");
+        std::env::set_var("KEYVY_MAXMEMORY", "2mb");
+        let path = file.path().to_str().unwrap();
+        let config = Config::load(&strings(&["--config", path, "--hz", "40"]));
+        std::env::remove_var("KEYVY_HZ");
+        std::env::remove_var("KEYVY_MAXMEMORY");
+
+        let config = config.unwrap();
+        assert_eq!(config.hz, 40);
+        assert_eq!(config.maxmemory, 2 * 1024 * 1024);
+        assert_eq!(config.loglevel, LogLevel::Warning);
+        assert_eq!(config.port, Config::default().port);
+        assert_eq!(config.config_file.as_deref(), Some(file.path()));
+    }
+
+    #[test]
+    fn rewrite_keeps_comments_and_order() {
+        let file = TempFile::new(
+            "rewrite.conf",
+            b"# keyvy settings\nport 7000\n\n  # memory\nmaxmemory 1mb\nhz 20\nhz 30\n",
+        );
+        let mut config = Config::default();
+        config.load_file(file.path()).unwrap();
+        config.set("maxmemory", "2mb").unwrap();
+        config.set("dbfilename", "my dump.kvy").unwrap();
+        config.set("requirepass", "").unwrap();
+        config.rewrite().unwrap();
+
+        let expected = "# keyvy settings\n\
+                        port 7000\n\
+                        \n  # memory\n\
+                        maxmemory 2097152\n\
+                        hz 30\n\
+                        dbfilename \"my dump.kvy\"\n";
+        assert_eq!(fs::read_to_string(file.path()).unwrap(), expected);
+
+        // The rewritten file loads back into the same settings
+        let mut loaded = Config::default();
+        loaded.load_file(file.path()).unwrap();
+        for name in PARAMETERS {
+            assert_eq!(loaded.get(name), config.get(name), "{}", name);
+        }
+    }
+}
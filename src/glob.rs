@@ -0,0 +1,168 @@
+/**
+ * Glob-style pattern matching as used by Redis: `*` matches any sequence,
+ * `?` any single byte, `[abc]`, `[^abc]` and `[a-z]` match a byte class and
+ * `\` escapes the next character.
+ */
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Pattern position right after the last `*` and the string position that
+    // star's match currently ends at. On a mismatch the star takes one more
+    // byte instead; earlier stars never need to, so this runs in linear space
+    // and at most quadratic time.
+    let mut star: Option<(usize, usize)> = None;
+
+    while p < pattern.len() || s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, s));
+            continue;
+        }
+        if let Some(len) = string
+            .get(s)
+            .and_then(|&byte| match_one(&pattern[p..], byte))
+        {
+            p += len;
+            s += 1;
+            continue;
+        }
+        match star {
+            Some((after_star, end)) if end < string.len() => {
+                star = Some((after_star, end + 1));
+                p = after_star;
+                s = end + 1;
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/**
+ * Match `byte` against the first element of `pattern`, which is not a `*`.
+ * Returns the length of that element if it matched.
+ */
+fn match_one(pattern: &[u8], byte: u8) -> Option<usize> {
+    let (matched, len) = match pattern {
+        [] => return None,
+        [b'?', ..] => (true, 1),
+        [b'[', class @ ..] => {
+            let (matched, rest) = match_class(class, byte);
+            (matched, pattern.len() - rest.len())
+        }
+        [b'\\', escaped, ..] => (*escaped == byte, 2),
+        [literal, ..] => (*literal == byte, 1),
+    };
+    matched.then_some(len)
+}
+
+/**
+ * Match `byte` against a `[...]` class whose opening bracket was already
+ * consumed. Returns whether it matched and the pattern after the class.
+ */
+fn match_class(pattern: &[u8], byte: u8) -> (bool, &[u8]) {
+    let (negate, mut i) = match pattern.first() {
+        Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == byte;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (low, high) = if pattern[i] <= pattern[i + 2] {
+                (pattern[i], pattern[i + 2])
+            } else {
+                (pattern[i + 2], pattern[i])
+            };
+            matched |= (low..=high).contains(&byte);
+            i += 3;
+        } else {
+            matched |= pattern[i] == byte;
+            i += 1;
+        }
+    }
+
+    // Skip the closing bracket; an unterminated class runs to the end of the pattern
+    let rest = if i < pattern.len() {
+        &pattern[i + 1..]
+    } else {
+        &pattern[i..]
+    };
+    (matched != negate, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, string: &str) -> bool {
+        matches(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn star_matches_any_sequence() {
+        assert!(glob("*", ""));
+        assert!(glob("*", "maxmemory"));
+        assert!(glob("max*", "maxmemory"));
+        assert!(glob("*memory", "maxmemory"));
+        assert!(glob("m*m*y", "maxmemory"));
+        assert!(glob("**y", "maxmemory"));
+        assert!(!glob("*x", "maxmemory"));
+        assert!(!glob("max*", "ma"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_byte() {
+        assert!(glob("h?", "hz"));
+        assert!(glob("?", "x"));
+        assert!(!glob("?", ""));
+        assert!(!glob("h?", "h"));
+        assert!(!glob("h?", "hzz"));
+        assert!(glob("*?", "a"));
+        assert!(!glob("*?", ""));
+    }
+
+    #[test]
+    fn classes_match_sets_and_ranges() {
+        assert!(glob("[abc]x", "bx"));
+        assert!(!glob("[abc]x", "dx"));
+        assert!(glob("[a-z]", "q"));
+        assert!(glob("[z-a]", "q"));
+        assert!(!glob("[a-z]", "Q"));
+        assert!(glob("[^a-z]", "Q"));
+        assert!(glob("[^a-z]", "1"));
+        assert!(!glob("[^a-z]", "q"));
+        assert!(!glob("[^a-z]", ""));
+        assert!(glob("[\\]]", "]"));
+    }
+
+    #[test]
+    fn backslash_escapes_the_next_byte() {
+        assert!(glob("a\\*b", "a*b"));
+        assert!(!glob("a\\*b", "axb"));
+        assert!(glob("\\?", "?"));
+        assert!(!glob("\\?", "x"));
+        assert!(glob("\\[x]", "[x]"));
+        // A trailing backslash has nothing to escape and matches itself
+        assert!(glob("a\\", "a\\"));
+    }
+
+    #[test]
+    fn unterminated_class_runs_to_the_end() {
+        assert!(glob("[abc", "a"));
+        assert!(glob("x[^abc", "xd"));
+        assert!(!glob("x[^abc", "xa"));
+        assert!(!glob("[abc", "ab"));
+        assert!(!glob("[", "a"));
+    }
+
+    #[test]
+    fn many_stars_do_not_backtrack_exponentially() {
+        let pattern = "*?".repeat(40) + "!";
+        let string = "x".repeat(200);
+        assert!(!glob(&pattern, &string));
+        assert!(glob(&pattern, &(string + "!")));
+    }
+}
@@ -0,0 +1,22 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::config::LogLevel;
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Notice as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/**
+ * Print a message if it is at least as important as the configured log level
+ */
+pub fn log(level: LogLevel, string: &str) {
+    if level as u8 >= LEVEL.load(Ordering::Relaxed) {
+        if level == LogLevel::Warning {
+            eprintln!("{}", string);
+        } else {
+            println!("{}", string);
+        }
+    }
+}
@@ -1,86 +1,246 @@
-use std::collections::HashMap;
-use std::io::{prelude::*, BufReader};
-use std::net::{TcpListener, TcpStream};
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
-use std::{str, thread};
+use std::{slice, str};
 
-static PASSWORD: &str = "password";
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+mod acl;
+mod aof;
+mod config;
+mod glob;
+mod logger;
+mod net;
+mod password;
+mod resp;
+mod snapshot;
+mod store;
+#[cfg(test)]
+mod testing;
+mod tls;
+
+use acl::{Acl, Denied};
+use aof::Aof;
+use config::{Config, LogLevel};
+use logger::log;
+use net::Listener;
+use resp::{Protocol, Reply};
+use store::{Shard, Store, StoreReadGuard};
+
+/**
+ * Commands that are refused once the keyspace reaches `maxmemory`
+ */
+const DENY_OOM: &[&str] = &["SET", "APPEND", "SETRANGE"];
+
+/**
+ * Most keys removed by one step of active expiry, i.e. while holding a shard lock
+ */
+const ACTIVE_EXPIRE_BATCH: usize = 64;
+
+/**
+ * State shared by all connections
+ */
+struct Server {
+    store: Arc<Store>,
+    config: RwLock<Config>,
+    acl: RwLock<Acl>,
+    /// Settings for new TLS connections, `None` without a TLS listener
+    tls: RwLock<Option<Arc<rustls::ServerConfig>>>,
+    aof: Aof,
+    /// Set while the server is saving its data to shut down
+    shutting_down: AtomicBool,
+    /// Number of connections currently open, across all workers
+    clients: AtomicUsize,
+}
 
 struct CacheEntry {
     expires_at: Option<Instant>,
-    value: String,
+    value: Vec<u8>,
 }
 
-impl CacheEntry {
-    fn new(ttl_seconds: u64, value: &str) -> CacheEntry {
-        let expires_at = if ttl_seconds == 0 {
-            None
-        } else {
-            Some(Instant::now() + Duration::from_secs(ttl_seconds))
-        };
+fn key_from_request(parts: &[Vec<u8>]) -> Vec<u8> {
+    parts[1].clone()
+}
 
-        CacheEntry {
-            expires_at,
-            value: value.to_string(),
-        }
+fn keys_from_request(parts: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    parts[1..].to_vec()
+}
+
+/**
+ * Handle DEL request
+ */
+fn handle_del(parts: &[Vec<u8>], store: &Store, aof: &Aof) -> Reply {
+    let keys = keys_from_request(parts);
+    let mut shards = store.write_many(&keys);
+    // A key that expired but wasn't reclaimed yet is already gone
+    let deleted: Vec<Vec<u8>> = keys
+        .into_iter()
+        .filter(|key| get_by_key(shards.shard(key), key).is_some())
+        .collect();
+
+    if let Err(e) = aof.append_del(&deleted) {
+        return aof_error(e);
+    }
+    for key in &deleted {
+        shards.shard(key).remove(key);
     }
+    Reply::Integer(deleted.len() as i64)
+}
 
-    fn new_from_request(parts: &Vec<&str>) -> CacheEntry {
-        let mut ttl = 0_u64;
-        let value;
+/**
+ * Options of a SET request: `SET key value [NX|XX] [GET] [EX|PX|EXAT|PXAT time|KEEPTTL]`
+ */
+#[derive(Default)]
+struct SetOptions {
+    nx: bool,
+    xx: bool,
+    get: bool,
+    keep_ttl: bool,
+    expires_at: Option<Instant>,
+}
 
-        if parts.len() == 4 {
-            ttl = parts[2].parse::<u64>().unwrap_or(0);
-            value = parts[3];
-        } else {
-            value = parts[2];
-        }
+impl SetOptions {
+    fn parse(options: &[Vec<u8>]) -> Result<SetOptions, Reply> {
+        let syntax_error = || Reply::error("ERR syntax error");
+        let mut parsed = SetOptions::default();
+        let mut expiry_given = false;
 
-        return CacheEntry::new(ttl, value);
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let option = String::from_utf8_lossy(option).to_uppercase();
+            match option.as_str() {
+                "NX" if !parsed.xx => parsed.nx = true,
+                "XX" if !parsed.nx => parsed.xx = true,
+                "GET" => parsed.get = true,
+                "KEEPTTL" if !expiry_given => parsed.keep_ttl = true,
+                "EX" | "PX" | "EXAT" | "PXAT" if !expiry_given && !parsed.keep_ttl => {
+                    let amount = parse_integer(options.next().ok_or_else(syntax_error)?)?;
+                    parsed.expires_at = Some(set_expiry("set", &option, amount)?);
+                    expiry_given = true;
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        Ok(parsed)
     }
 }
 
-fn key_from_request(parts: &Vec<&str>) -> String {
-    return parts[1].to_string();
+/**
+ * Turn the time given to the EX, PX, EXAT or PXAT option of `command` into an
+ * instant. An absolute time in the past yields a key that is already expired.
+ */
+fn set_expiry(command: &str, option: &str, amount: i64) -> Result<Instant, Reply> {
+    let millis = match option {
+        "EX" | "EXAT" => amount.checked_mul(1000),
+        _ => Some(amount),
+    };
+    // A relative time must also leave room to be stored as absolute Unix time
+    let now_millis = store::to_unix_millis(Instant::now()) as i64;
+    let fits =
+        |millis: &i64| !matches!(option, "EX" | "PX") || now_millis.checked_add(*millis).is_some();
+    let Some(millis) = millis.filter(|millis| amount > 0 && fits(millis)) else {
+        return Err(Reply::Error(format!(
+            "ERR invalid expire time in '{}' command",
+            command
+        )));
+    };
+
+    // Relative times stay on the monotonic clock, only absolute ones go
+    // through wall-clock time
+    Ok(match option {
+        "EX" | "PX" => Instant::now() + Duration::from_millis(millis as u64),
+        _ => store::from_unix_millis(millis as u64),
+    })
 }
 
-fn keys_from_request(parts: &Vec<&str>) -> Vec<String> {
-    let mut keys = Vec::new();
-    for i in 1..parts.len() {
-        keys.push(parts[i].to_string());
-    }
-    return keys;
+/**
+ * Whether a SET request uses the legacy `SET key ttl value` form: exactly
+ * four arguments, a whole number of seconds and a value that is not one of
+ * the option keywords.
+ */
+fn is_legacy_set(parts: &[Vec<u8>]) -> bool {
+    const KEYWORDS: &[&str] = &["NX", "XX", "GET", "KEEPTTL", "EX", "PX", "EXAT", "PXAT"];
+    parts.len() == 4
+        && !parts[2].is_empty()
+        && parts[2].iter().all(u8::is_ascii_digit)
+        && !KEYWORDS.contains(&String::from_utf8_lossy(&parts[3]).to_uppercase().as_str())
 }
 
 /**
- * Handle DEL request
+ * Handle SET request
  */
-fn handle_del(parts: &Vec<&str>, store: &mut HashMap<String, CacheEntry>, writer: &mut TcpStream) {
-    let keys = keys_from_request(&parts);
-    let mut deleted = 0;
-    for key in keys {
-        let result = store.remove(&key);
-        if Option::is_some(&result) {
-            deleted += 1;
+fn handle_set(parts: &[Vec<u8>], store: &mut Shard, aof: &Aof) -> Reply {
+    let key = key_from_request(parts);
+    let (value, options) = if is_legacy_set(parts) {
+        // A TTL of 0 means no expiry in this form
+        let expires_at = match parse_integer(&parts[2]) {
+            Ok(0) => None,
+            Ok(seconds) => match set_expiry("set", "EX", seconds) {
+                Ok(expires_at) => Some(expires_at),
+                Err(reply) => return reply,
+            },
+            Err(reply) => return reply,
+        };
+        let options = SetOptions {
+            expires_at,
+            ..SetOptions::default()
+        };
+        (&parts[3], options)
+    } else {
+        match SetOptions::parse(&parts[3..]) {
+            Ok(options) => (&parts[2], options),
+            Err(reply) => return reply,
         }
+    };
+
+    let previous = get_by_key(store, &key);
+    // Only copy the previous value out if the client asked for it
+    let old_value = match previous {
+        Some(entry) if options.get => Reply::Bulk(entry.value.clone()),
+        _ => Reply::Null,
+    };
+
+    if (options.nx && previous.is_some()) || (options.xx && previous.is_none()) {
+        return old_value;
     }
 
-    writeln!(writer, ":{}", deleted).ok();
+    let expires_at = if options.keep_ttl {
+        previous.and_then(|entry| entry.expires_at)
+    } else {
+        options.expires_at
+    };
+    let entry = CacheEntry {
+        expires_at,
+        value: value.clone(),
+    };
+    if let Err(e) = aof.append_set(&key, &entry) {
+        return aof_error(e);
+    }
+    store.insert(key, entry);
+
+    if options.get {
+        old_value
+    } else {
+        Reply::ok()
+    }
 }
 
 /**
- * Handle SET request
+ * Reply to a change that could not be logged to the AOF and was not applied
  */
-fn handle_set(parts: &Vec<&str>, store: &mut HashMap<String, CacheEntry>, writer: &mut TcpStream) {
-    store.insert(
-        key_from_request(&parts),
-        CacheEntry::new_from_request(&parts),
-    );
-    writeln!(writer, "+OK").ok();
+fn aof_error(e: io::Error) -> Reply {
+    Reply::Error(format!("MISCONF Errors writing to the AOF file: {}", e))
 }
 
-fn get_by_key<'a>(store: &'a HashMap<String, CacheEntry>, key: &str) -> Option<&'a CacheEntry> {
+fn get_by_key<'a>(store: &'a Shard, key: &[u8]) -> Option<&'a CacheEntry> {
     match store.get(key) {
         Some(entry) => {
             if let Some(expiration) = entry.expires_at {
@@ -97,201 +257,1976 @@ fn get_by_key<'a>(store: &'a HashMap<String, CacheEntry>, key: &str) -> Option<&
 /**
  * Handle GET request
  */
-fn handle_get(parts: &Vec<&str>, store: &HashMap<String, CacheEntry>, writer: &mut TcpStream) {
+fn handle_get(parts: &[Vec<u8>], store: &Shard) -> Reply {
     let key = &key_from_request(parts);
     match get_by_key(store, key) {
-        Some(entry) => {
-            writeln!(writer, "${}\r\n{}", entry.value.len(), entry.value).ok();
+        Some(entry) => Reply::Bulk(entry.value.clone()),
+        None => Reply::Null,
+    }
+}
+
+/**
+ * Remaining time to live of `key` in milliseconds, `-2` if the key does not
+ * exist and `-1` if it has no expiry. Expiry is checked against a single
+ * clock reading, so a key expiring meanwhile can't produce a negative duration.
+ */
+fn ttl_millis(store: &Shard, key: &[u8]) -> i64 {
+    let now = Instant::now();
+    match store.get(key) {
+        None => -2,
+        Some(entry) => match entry.expires_at {
+            None => -1,
+            Some(expiration) if expiration <= now => -2,
+            // Round to the nearest millisecond rather than truncating, so a
+            // freshly set PX 100 reads back as 100
+            Some(expiration) => {
+                let millis = ((expiration - now).as_micros() + 500) / 1000;
+                i64::try_from(millis).unwrap_or(i64::MAX)
+            }
+        },
+    }
+}
+
+/**
+ * Handle TTL request
+ */
+fn handle_ttl(parts: &[Vec<u8>], store: &Shard) -> Reply {
+    let millis = ttl_millis(store, &key_from_request(parts));
+    if millis < 0 {
+        return Reply::Integer(millis);
+    }
+    // Round to the nearest second
+    Reply::Integer(millis.saturating_add(500) / 1000)
+}
+
+/**
+ * Handle PTTL request
+ */
+fn handle_pttl(parts: &[Vec<u8>], store: &Shard) -> Reply {
+    Reply::Integer(ttl_millis(store, &key_from_request(parts)))
+}
+
+/**
+ * Parse an integer argument
+ */
+fn parse_integer(arg: &[u8]) -> Result<i64, Reply> {
+    str::from_utf8(arg)
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .ok_or_else(|| Reply::error("ERR value is not an integer or out of range"))
+}
+
+/**
+ * Handle EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT requests:
+ * `<COMMAND> <key> <time> [NX|XX|GT|LT]`
+ */
+fn handle_expire(parts: &[Vec<u8>], store: &mut Shard, aof: &Aof, command: &str) -> Reply {
+    let key = key_from_request(parts);
+    let amount = match parse_integer(&parts[2]) {
+        Ok(amount) => amount,
+        Err(reply) => return reply,
+    };
+
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for option in &parts[3..] {
+        match String::from_utf8_lossy(option).to_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "GT" => gt = true,
+            "LT" => lt = true,
+            other => return Reply::Error(format!("ERR Unsupported option {}", other)),
+        }
+    }
+    if nx && (xx || gt || lt) {
+        return Reply::error("ERR NX and XX, GT or LT options at the same time are not compatible");
+    }
+    if gt && lt {
+        return Reply::error("ERR GT and LT options at the same time are not compatible");
+    }
+
+    // Everything is compared as absolute Unix time in milliseconds
+    let now_millis = store::to_unix_millis(Instant::now()) as i64;
+    let at = match command {
+        "EXPIRE" => amount
+            .checked_mul(1000)
+            .and_then(|ms| ms.checked_add(now_millis)),
+        "PEXPIRE" => amount.checked_add(now_millis),
+        "EXPIREAT" => amount.checked_mul(1000),
+        _ => Some(amount),
+    };
+    let Some(at) = at else {
+        return Reply::Error(format!(
+            "ERR invalid expire time in '{}' command",
+            command.to_lowercase()
+        ));
+    };
+
+    let current = match get_by_key(store, &key) {
+        Some(entry) => entry
+            .expires_at
+            .map(|expiration| store::to_unix_millis(expiration) as i64),
+        None => return Reply::Integer(0),
+    };
+
+    // A key without expiry counts as living forever for GT and LT
+    let allowed = match current {
+        Some(current) => !nx && (!gt || at > current) && (!lt || at < current),
+        None => !xx && !gt,
+    };
+    if !allowed {
+        return Reply::Integer(0);
+    }
+
+    if at <= now_millis {
+        if let Err(e) = aof.append_del(slice::from_ref(&key)) {
+            return aof_error(e);
         }
-        None => {
-            writeln!(writer, "$-1").ok();
+        store.remove(&key);
+    } else {
+        let expires_at = store::from_unix_millis(at as u64);
+        if let Err(e) = aof.append_expire(&key, expires_at) {
+            return aof_error(e);
         }
+        store.set_expires_at(&key, Some(expires_at));
     }
+    Reply::Integer(1)
 }
 
 /**
- * Handle TTL request
+ * Handle PERSIST request
  */
-fn handle_ttl(parts: &Vec<&str>, store: &HashMap<String, CacheEntry>, writer: &mut TcpStream) {
-    let key = &key_from_request(parts);
-    match get_by_key(store, key) {
-        Some(entry) => {
-            if let Some(expiration) = entry.expires_at {
-                let seconds_left = expiration
-                    .checked_duration_since(Instant::now())
-                    .unwrap()
-                    .as_secs();
-                writeln!(writer, "{}", seconds_left).ok();
-            } else {
-                writeln!(writer, "-1").ok();
+fn handle_persist(parts: &[Vec<u8>], store: &mut Shard, aof: &Aof) -> Reply {
+    let key = key_from_request(parts);
+    match get_by_key(store, &key) {
+        Some(entry) if entry.expires_at.is_some() => {
+            if let Err(e) = aof.append(&[b"PERSIST", &key]) {
+                return aof_error(e);
             }
+            store.set_expires_at(&key, None);
+            Reply::Integer(1)
         }
-        None => {
-            writeln!(writer, "-1").ok();
+        _ => Reply::Integer(0),
+    }
+}
+
+/**
+ * Handle EXPIRETIME and PEXPIRETIME requests: the absolute Unix time at which
+ * the key expires, `-1` if it has no expiry and `-2` if it does not exist
+ */
+fn handle_expiretime(parts: &[Vec<u8>], store: &Shard, millis: bool) -> Reply {
+    let key = key_from_request(parts);
+    match get_by_key(store, &key) {
+        Some(entry) => match entry.expires_at {
+            Some(expiration) => {
+                let at = store::to_unix_millis(expiration) as i64;
+                Reply::Integer(if millis { at } else { at / 1000 })
+            }
+            None => Reply::Integer(-1),
+        },
+        None => Reply::Integer(-2),
+    }
+}
+
+/**
+ * Remove `key` if it has expired but was not cleaned up yet, so commands that
+ * modify a value in place start from nothing rather than the stale value
+ */
+fn remove_if_expired(store: &mut Shard, key: &[u8]) {
+    if store.get(key).is_some() && get_by_key(store, key).is_none() {
+        store.remove(key);
+    }
+}
+
+/**
+ * Refuse to grow a string value beyond `max_len` bytes
+ */
+fn check_string_length(len: usize, max_len: usize) -> Result<(), Reply> {
+    if len > max_len {
+        return Err(Reply::error(
+            "ERR string exceeds maximum allowed size (proto-max-bulk-len)",
+        ));
+    }
+    Ok(())
+}
+
+/**
+ * Handle APPEND request: `APPEND <key> <value>`
+ */
+fn handle_append(parts: &[Vec<u8>], store: &mut Shard, aof: &Aof, max_len: usize) -> Reply {
+    let key = key_from_request(parts);
+    remove_if_expired(store, &key);
+    let len = store.get(&key).map_or(0, |entry| entry.value.len());
+    if let Err(reply) = check_string_length(len.saturating_add(parts[2].len()), max_len) {
+        return reply;
+    }
+
+    // Only the appended part is logged, not the whole value
+    if let Err(e) = aof.append(&[b"APPEND", &key, &parts[2]]) {
+        return aof_error(e);
+    }
+    Reply::Integer(store.append(&key, &parts[2]) as i64)
+}
+
+/**
+ * Handle STRLEN request
+ */
+fn handle_strlen(parts: &[Vec<u8>], store: &Shard) -> Reply {
+    let key = key_from_request(parts);
+    Reply::Integer(get_by_key(store, &key).map_or(0, |entry| entry.value.len() as i64))
+}
+
+/**
+ * Handle GETRANGE request: `GETRANGE <key> <start> <end>`. Both ends are
+ * inclusive, negative offsets count from the end of the value.
+ */
+fn handle_getrange(parts: &[Vec<u8>], store: &Shard) -> Reply {
+    let (start, end) = match (parse_integer(&parts[2]), parse_integer(&parts[3])) {
+        (Ok(start), Ok(end)) => (start, end),
+        (Err(reply), _) | (_, Err(reply)) => return reply,
+    };
+    let key = key_from_request(parts);
+    let value = match get_by_key(store, &key) {
+        Some(entry) => &entry.value,
+        None => return Reply::Bulk(Vec::new()),
+    };
+
+    let len = value.len() as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 {
+        (len + end).max(0)
+    } else {
+        end.min(len - 1)
+    };
+    if len == 0 || start > end {
+        return Reply::Bulk(Vec::new());
+    }
+    Reply::Bulk(value[start as usize..=end as usize].to_vec())
+}
+
+/**
+ * Handle SETRANGE request: `SETRANGE <key> <offset> <value>`. A value shorter
+ * than the offset is padded with zero bytes first.
+ */
+fn handle_setrange(parts: &[Vec<u8>], store: &mut Shard, aof: &Aof, max_len: usize) -> Reply {
+    let offset = match parse_integer(&parts[2]) {
+        Ok(offset) if offset >= 0 => offset as usize,
+        Ok(_) => return Reply::error("ERR offset is out of range"),
+        Err(reply) => return reply,
+    };
+    let data = &parts[3];
+    if let Err(reply) = check_string_length(offset.saturating_add(data.len()), max_len) {
+        return reply;
+    }
+
+    let key = key_from_request(parts);
+    remove_if_expired(store, &key);
+    // Writing nothing changes nothing, and doesn't create a missing key
+    if data.is_empty() {
+        return Reply::Integer(store.get(&key).map_or(0, |entry| entry.value.len() as i64));
+    }
+
+    if let Err(e) = aof.append(&[b"SETRANGE", &key, offset.to_string().as_bytes(), data]) {
+        return aof_error(e);
+    }
+    Reply::Integer(store.set_range(&key, offset, data) as i64)
+}
+
+/**
+ * Handle GETDEL request
+ */
+fn handle_getdel(parts: &[Vec<u8>], store: &mut Shard, aof: &Aof) -> Reply {
+    let key = key_from_request(parts);
+    if get_by_key(store, &key).is_none() {
+        return Reply::Null;
+    }
+    if let Err(e) = aof.append_del(slice::from_ref(&key)) {
+        return aof_error(e);
+    }
+    match store.remove(&key) {
+        Some(entry) => Reply::Bulk(entry.value),
+        None => Reply::Null,
+    }
+}
+
+/**
+ * Parse the options of a GETEX request: `[EX|PX|EXAT|PXAT <time>|PERSIST]`.
+ * Returns `None` to leave the expiry alone and `Some(None)` to remove it.
+ */
+fn parse_getex_expiry(options: &[Vec<u8>]) -> Result<Option<Option<Instant>>, Reply> {
+    let syntax_error = || Reply::error("ERR syntax error");
+    let option = match options.first() {
+        Some(option) => String::from_utf8_lossy(option).to_uppercase(),
+        None => return Ok(None),
+    };
+    match (option.as_str(), &options[1..]) {
+        ("PERSIST", []) => Ok(Some(None)),
+        ("EX" | "PX" | "EXAT" | "PXAT", [amount]) => {
+            let amount = parse_integer(amount)?;
+            Ok(Some(Some(set_expiry("getex", &option, amount)?)))
         }
+        _ => Err(syntax_error()),
     }
 }
 
-fn main() -> std::io::Result<()> {
-    let store = Arc::new(RwLock::new(HashMap::new()));
+/**
+ * Handle GETEX request: `GETEX <key> [EX|PX|EXAT|PXAT <time>|PERSIST]`.
+ * Returns the value like GET and changes its expiry in the same step.
+ */
+fn handle_getex(parts: &[Vec<u8>], store: &mut Shard, aof: &Aof) -> Reply {
+    let expiry = match parse_getex_expiry(&parts[2..]) {
+        Ok(expiry) => expiry,
+        Err(reply) => return reply,
+    };
+    let key = key_from_request(parts);
+    let (value, had_expiry) = match get_by_key(store, &key) {
+        Some(entry) => (entry.value.clone(), entry.expires_at.is_some()),
+        None => return Reply::Null,
+    };
 
-    // Cleanup expired entries every 30 seconds
-    periodic_cleanup(&store, 30);
+    match expiry {
+        // An absolute time in the past deletes the key, as with EXPIREAT
+        Some(Some(expires_at)) if expires_at <= Instant::now() => {
+            if let Err(e) = aof.append_del(slice::from_ref(&key)) {
+                return aof_error(e);
+            }
+            store.remove(&key);
+        }
+        Some(Some(expires_at)) => {
+            if let Err(e) = aof.append_expire(&key, expires_at) {
+                return aof_error(e);
+            }
+            store.set_expires_at(&key, Some(expires_at));
+        }
+        Some(None) if had_expiry => {
+            if let Err(e) = aof.append(&[b"PERSIST", &key]) {
+                return aof_error(e);
+            }
+            store.set_expires_at(&key, None);
+        }
+        _ => {}
+    }
+    Reply::Bulk(value)
+}
 
-    // Bind the TCP listener to localhost:7878
-    let listener = TcpListener::bind("127.0.0.1:7878")?;
-    println!("Server listening on 127.0.0.1:7878");
+/**
+ * Handle AUTH request: `AUTH <password>` for the default user or `AUTH <username> <password>`
+ */
+fn handle_auth(parts: &[Vec<u8>], session: &mut Session, acl: &Acl) -> Reply {
+    let (name, password) = match parts.len() {
+        2 => (acl::DEFAULT_USER.to_string(), &parts[1]),
+        3 => (String::from_utf8_lossy(&parts[1]).into_owned(), &parts[2]),
+        _ => return Reply::error("ERR syntax error"),
+    };
+    if !acl.authenticate(&name, password) {
+        return Reply::error("WRONGPASS invalid username-password pair or user is disabled.");
+    }
+    session.user = Some(name);
+    Reply::ok()
+}
+
+/**
+ * Handle HELLO request: `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+ */
+fn handle_hello(parts: &[Vec<u8>], session: &mut Session, acl: &Acl) -> Reply {
+    let mut protocol = session.protocol;
+    if parts.len() > 1 {
+        protocol = match str::from_utf8(&parts[1])
+            .ok()
+            .and_then(|version| version.parse::<i64>().ok())
+        {
+            Some(2) => Protocol::Resp2,
+            Some(3) => Protocol::Resp3,
+            Some(_) => return Reply::error("NOPROTO unsupported protocol version"),
+            None => return Reply::error("ERR Protocol version is not an integer or out of range"),
+        };
+    }
 
-    // Accept incoming connections in a loop
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let store = Arc::clone(&store);
-                thread::spawn(move || handle_connection(stream, store));
+    let mut i = 2;
+    while i < parts.len() {
+        let option = String::from_utf8_lossy(&parts[i]).to_uppercase();
+        if option == "AUTH" && i + 2 < parts.len() {
+            let name = String::from_utf8_lossy(&parts[i + 1]).into_owned();
+            if !acl.authenticate(&name, &parts[i + 2]) {
+                return Reply::error(
+                    "WRONGPASS invalid username-password pair or user is disabled.",
+                );
             }
+            session.user = Some(name);
+            i += 3;
+        } else if option == "SETNAME" && i + 1 < parts.len() {
+            // Client names are accepted for compatibility but not tracked
+            i += 2;
+        } else {
+            return Reply::Error(format!("ERR Syntax error in HELLO option '{}'", option));
+        }
+    }
+
+    if session.user.is_none() {
+        return Reply::error("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time");
+    }
+
+    session.protocol = protocol;
+    Reply::Map(vec![
+        (Reply::bulk("server"), Reply::bulk("keyvy")),
+        (
+            Reply::bulk("version"),
+            Reply::bulk(env!("CARGO_PKG_VERSION")),
+        ),
+        (Reply::bulk("proto"), Reply::Integer(protocol.version())),
+        (Reply::bulk("mode"), Reply::bulk("standalone")),
+        (Reply::bulk("role"), Reply::bulk("master")),
+        (Reply::bulk("modules"), Reply::Array(Vec::new())),
+    ])
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", config::usage());
+        return Ok(());
+    }
+
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            eprintln!("{}", config::usage());
+            std::process::exit(1);
+        }
+    };
+    logger::set_level(config.loglevel);
+    if let Some(path) = &config.config_file {
+        log(
+            LogLevel::Notice,
+            &format!("Configuration loaded from {}", path.display()),
+        );
+    }
+
+    // The append-only file holds the most recent data, so it wins over the snapshot
+    let (loaded, path) = if config.appendonly {
+        let path = config.aof_path();
+        (aof::load(&path, config.aof_load_truncated), path)
+    } else {
+        let path = config.snapshot_path();
+        (snapshot::load(&path), path)
+    };
+    let store = match loaded {
+        Ok(store) => store,
+        Err(e) => {
+            log(
+                LogLevel::Warning,
+                &format!("Can't load {}: {}", path.display(), e),
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let aof = if config.appendonly {
+        Aof::open(&config.aof_path(), config.appendfsync)?
+    } else {
+        Aof::disabled()
+    };
+
+    let mut acl = Acl::new(&config.requirepass);
+    if let Some(path) = config.acl_path() {
+        if let Err(e) = acl.load(&path) {
+            log(
+                LogLevel::Warning,
+                &format!("Can't load ACL file {}: {}", path.display(), e),
+            );
+            std::process::exit(1);
+        }
+    }
+
+    let tls = if config.tls_port != 0 {
+        match tls::load(&config) {
+            Ok(tls) => Some(tls),
             Err(e) => {
-                eprintln!("Error: {}", e);
+                log(LogLevel::Warning, &format!("Can't set up TLS: {}", e));
+                std::process::exit(1);
             }
         }
-    }
+    } else {
+        None
+    };
 
+    let server = Arc::new(Server {
+        store: Arc::new(store),
+        config: RwLock::new(config),
+        acl: RwLock::new(acl),
+        tls: RwLock::new(tls),
+        aof,
+        shutting_down: AtomicBool::new(false),
+        clients: AtomicUsize::new(0),
+    });
+
+    // Remove expired keys in the background
+    active_expire(&server);
+    periodic_aof(&server);
+
+    let (listeners, io_threads) = {
+        let config = server.config.read().unwrap();
+        (bind_listeners(&config), config.io_threads)
+    };
+    let listeners = match listeners {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            log(LogLevel::Warning, &format!("Can't listen: {}", e));
+            std::process::exit(1);
+        }
+    };
+    handle_signals(&server)?;
+    net::serve(listeners, Arc::clone(&server), io_threads)?;
+
+    let unixsocket = server.config.read().unwrap().unixsocket.clone();
+    if !unixsocket.is_empty() {
+        fs::remove_file(&unixsocket).ok();
+    }
+    log(LogLevel::Warning, "Keyvy is now ready to exit, bye bye...");
     Ok(())
 }
 
-fn periodic_cleanup(store: &Arc<RwLock<HashMap<String, CacheEntry>>>, interval: u64) {
-    let store_for_cleanup = Arc::clone(&store);
+/**
+ * Shut down gracefully on SIGTERM and SIGINT
+ */
+fn handle_signals(server: &Arc<Server>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    let server = Arc::clone(server);
     std::thread::spawn(move || {
-        use std::time::Duration;
-        loop {
-            std::thread::sleep(Duration::from_secs(interval));
-
-            let mut map = store_for_cleanup.write().unwrap();
-
-            // Retain only non-expired entries
-            map.retain(|_k, entry| {
-                match entry.expires_at {
-                    Some(exp) => Instant::now() < exp,
-                    None => true, // no TTL
-                }
-            });
+        for signal in signals.forever() {
+            let name = if signal == SIGINT {
+                "SIGINT"
+            } else {
+                "SIGTERM"
+            };
+            log(
+                LogLevel::Warning,
+                &format!("Received {} scheduling shutdown...", name),
+            );
+            // On failure the server keeps running, the reason is logged
+            shutdown(&server, ShutdownSave::Default).ok();
         }
     });
+    Ok(())
 }
 
-fn log(string: &str) {
-    println!("{}", string);
+/**
+ * Whether to write a snapshot when shutting down
+ */
+#[derive(Clone, Copy)]
+enum ShutdownSave {
+    /// Only without an append-only file, which has all data already
+    Default,
+    Save,
+    NoSave,
 }
 
-fn check_authenticated(writer: &mut TcpStream, authendificated: bool) -> bool {
-    if !authendificated {
-        writeln!(writer, "-NOAUTH Authentication required.").ok();
+/**
+ * Stop the server without losing data: new commands are refused, writes that
+ * are running finish, the append-only file is synced to disk and, depending
+ * on `save`, a snapshot is written. Then the listeners are closed and pending
+ * replies sent. Fails, and leaves the server running, if the data can't be
+ * saved.
+ */
+fn shutdown(server: &Server, save: ShutdownSave) -> Result<(), String> {
+    if server.shutting_down.swap(true, Ordering::SeqCst) {
+        return Err("Shutdown already in progress".to_string());
+    }
+
+    // Waits for running writes and keeps further ones out
+    let store = server.store.read_all();
+    if let Err(e) = save_for_shutdown(server, &store, save) {
+        log(LogLevel::Warning, &e);
+        log(
+            LogLevel::Warning,
+            "Errors trying to shut down the server, check the logs for more information",
+        );
+        server.shutting_down.store(false, Ordering::SeqCst);
+        return Err(e);
     }
 
-    return authendificated;
+    // Keep the keyspace locked until the process exits, so writes that were
+    // already waiting for the lock can't change it after it was saved
+    std::mem::forget(store);
+    net::stop();
+    Ok(())
 }
 
-fn check_password(writer: &mut TcpStream, parts: &Vec<&str>) -> bool {
-    if parts[1] == PASSWORD {
-        writeln!(writer, "+OK").ok();
-        return true;
+fn save_for_shutdown(
+    server: &Server,
+    store: &StoreReadGuard,
+    save: ShutdownSave,
+) -> Result<(), String> {
+    let (appendonly, path) = {
+        let config = server.config.read().unwrap();
+        (config.appendonly, config.snapshot_path())
+    };
+
+    if appendonly {
+        log(LogLevel::Notice, "Syncing the AOF file before exiting.");
+        server
+            .aof
+            .sync()
+            .map_err(|e| format!("Error syncing the AOF file: {}", e))?;
     }
 
-    writeln!(writer, "-ERR invalid password").ok();
-    return false;
+    let save = match save {
+        ShutdownSave::Default => !appendonly,
+        ShutdownSave::Save => true,
+        ShutdownSave::NoSave => false,
+    };
+    if save {
+        if snapshot::background_save_in_progress() {
+            return Err("Background save already in progress".to_string());
+        }
+        log(
+            LogLevel::Notice,
+            "Saving the final snapshot before exiting.",
+        );
+        snapshot::write_atomically(&path, &snapshot::serialize(store))
+            .map_err(|e| format!("Error saving {}: {}", path.display(), e))?;
+        log(LogLevel::Notice, "DB saved on disk");
+    }
+    Ok(())
 }
 
-fn check_params(writer: &mut TcpStream, parts: &Vec<&str>, command: &str) -> bool {
-    if parts.len() < 2 {
-        writeln!(writer, "-ERR wrong number of arguments for '{}'", command).ok();
-        return false;
+/**
+ * Open every configured listener: plain TCP on `port` and TLS on `tls-port`
+ * for every `bind` address, and the Unix socket `unixsocket`, skipping those
+ * set to `0` or left empty.
+ */
+fn bind_listeners(config: &Config) -> io::Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+    for entry in &config.bind {
+        let (host, explicit_port) = config::split_port(entry).unwrap_or((entry, None));
+        let plain_port = explicit_port.unwrap_or(config.port);
+        for (port, tls) in [(plain_port, false), (config.tls_port, true)] {
+            if port == 0 {
+                continue;
+            }
+            let mut addresses: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+            addresses.sort();
+            addresses.dedup();
+            for address in addresses {
+                listeners.push(Listener::Tcp {
+                    socket: net::listen(address)?,
+                    tls,
+                });
+                let kind = if tls { " (TLS)" } else { "" };
+                log(
+                    LogLevel::Notice,
+                    &format!("Server listening on {}{}", address, kind),
+                );
+            }
+        }
     }
-    return true;
+
+    if !config.unixsocket.is_empty() {
+        let path = Path::new(&config.unixsocket);
+        // A socket file left behind by an earlier run would make bind fail
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let socket = UnixListener::bind(path)?;
+        if config.unixsocketperm != 0 {
+            fs::set_permissions(path, fs::Permissions::from_mode(config.unixsocketperm))?;
+        }
+        listeners.push(Listener::Unix(socket));
+        log(
+            LogLevel::Notice,
+            &format!("Server listening on unix socket {}", path.display()),
+        );
+    }
+
+    if listeners.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Nothing to listen on, set port, tls-port or unixsocket",
+        ));
+    }
+    Ok(listeners)
 }
 
-fn handle_connection(stream: TcpStream, store: Arc<RwLock<HashMap<String, CacheEntry>>>) {
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
-    let mut writer = stream;
-    let mut authendificated = false;
+/**
+ * Remove expired keys in the background, `hz` times per second. Each step
+ * holds one shard's write lock for at most `ACTIVE_EXPIRE_BATCH` keys, so
+ * clients are never stalled for long; a cycle visits every shard and keeps
+ * stepping through one while it finds full batches of expired keys, up to a
+ * quarter of its period.
+ */
+fn active_expire(server: &Arc<Server>) {
+    let server = Arc::clone(server);
+    std::thread::spawn(move || loop {
+        // Re-read the frequency every cycle so CONFIG SET takes effect
+        let period = Duration::from_millis(1000 / server.config.read().unwrap().hz);
+        std::thread::sleep(period);
 
-    loop {
-        let mut buffer = String::new();
-        let bytes_read = match reader.read_line(&mut buffer) {
-            Ok(n) => n,
-            Err(_) => {
-                log("Error reading client, closing connection");
-                return;
+        let started = Instant::now();
+        for index in 0..store::SHARDS {
+            loop {
+                let expired = {
+                    let mut shard = server.store.write_shard(index);
+                    let expired = shard.remove_expired(Instant::now(), ACTIVE_EXPIRE_BATCH);
+                    // Expired keys are gone whether or not this is logged
+                    let _ = server.aof.append_del(&expired);
+                    expired
+                };
+                if expired.len() < ACTIVE_EXPIRE_BATCH || started.elapsed() >= period / 4 {
+                    break;
+                }
             }
-        };
+        }
+    });
+}
+
+/**
+ * Once per second: sync the append-only file and rewrite it when it grew too much
+ */
+fn periodic_aof(server: &Arc<Server>) {
+    let server = Arc::clone(server);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(1));
+        server.aof.fsync_if_due();
 
-        if bytes_read == 0 {
-            log("Zero bytes, connection closed");
-            return;
+        let (percentage, min_size) = {
+            let config = server.config.read().unwrap();
+            (
+                config.auto_aof_rewrite_percentage,
+                config.auto_aof_rewrite_min_size as u64,
+            )
+        };
+        if server.aof.rewrite_due(percentage, min_size) {
+            if let Err(e) = server.aof.background_rewrite(&server.store) {
+                log(
+                    LogLevel::Warning,
+                    &format!("Can't start automatic AOF rewrite: {}", e),
+                );
+            }
         }
+    });
+}
 
-        let trimmed = buffer.trim();
-        if trimmed.is_empty() {
-            log("Zero bytes when trimmed, connection closed");
-            return;
+/**
+ * Handle SAVE request
+ */
+fn handle_save(server: &Server) -> Reply {
+    if snapshot::background_save_in_progress() {
+        return Reply::error("ERR Background save already in progress");
+    }
+    let path = server.config.read().unwrap().snapshot_path();
+    match snapshot::save(&server.store, &path) {
+        Ok(()) => Reply::ok(),
+        Err(e) => {
+            log(
+                LogLevel::Warning,
+                &format!("Error saving DB on disk: {}", e),
+            );
+            Reply::Error(format!("ERR {}", e))
         }
+    }
+}
+
+/**
+ * Handle BGSAVE request
+ */
+fn handle_bgsave(server: &Server) -> Reply {
+    let path = server.config.read().unwrap().snapshot_path();
+    match snapshot::background_save(&server.store, &path) {
+        Ok(()) => Reply::Simple("Background saving started".to_string()),
+        Err(e) => Reply::Error(format!("ERR {}", e)),
+    }
+}
+
+/**
+ * Handle BGREWRITEAOF request
+ */
+fn handle_bgrewriteaof(server: &Server) -> Reply {
+    match server.aof.background_rewrite(&server.store) {
+        Ok(()) => Reply::Simple("Background append only file rewriting started".to_string()),
+        Err(e) => Reply::Error(format!("ERR {}", e)),
+    }
+}
 
-        let parts: Vec<&str> = trimmed.split_whitespace().collect();
-        if parts.is_empty() {
-            writeln!(writer, "-ERR empty command").ok();
+/**
+ * Handle CONFIG request: `CONFIG GET <pattern> [<pattern> ...]`,
+ * `CONFIG SET <name> <value> [<name> <value> ...]` and `CONFIG REWRITE`
+ */
+fn handle_config(parts: &[Vec<u8>], server: &Server) -> Reply {
+    let config = &server.config;
+    let subcommand = String::from_utf8_lossy(&parts[1]).to_uppercase();
+    match subcommand.as_str() {
+        "GET" if parts.len() >= 3 => {
+            // Match the patterns before taking the lock, so a slow pattern
+            // can't hold up a CONFIG SET and every request queued behind it
+            let names: Vec<&str> = config::PARAMETERS
+                .iter()
+                .copied()
+                .filter(|name| {
+                    parts[2..].iter().any(|pattern| {
+                        glob::matches(&pattern.to_ascii_lowercase(), name.as_bytes())
+                    })
+                })
+                .collect();
+            let config = config.read().unwrap();
+            let pairs = names
+                .into_iter()
+                .map(|name| {
+                    let value = config.get(name).unwrap_or_default();
+                    (Reply::bulk(name), Reply::bulk(&value))
+                })
+                .collect();
+            Reply::Map(pairs)
         }
 
-        let command = parts[0].to_uppercase();
-        match command.as_str() {
-            "AUTH" => {
-                if !check_params(&mut writer, &parts, &command) {
-                    continue;
-                }
-                authendificated = check_password(&mut writer, &parts);
-            }
+        "SET" if parts.len() >= 4 && parts.len().is_multiple_of(2) => {
+            let mut config = config.write().unwrap();
 
-            "PING" => {
-                writeln!(writer, "+PONG").ok();
+            // Apply every change to a copy first, so a bad value leaves the
+            // configuration untouched
+            let mut updated = config.clone();
+            for pair in parts[2..].chunks(2) {
+                let name = String::from_utf8_lossy(&pair[0]).to_lowercase();
+                let value = String::from_utf8_lossy(&pair[1]);
+                if !config::PARAMETERS.contains(&name.as_str()) {
+                    return Reply::Error(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        name
+                    ));
+                }
+                if config::IMMUTABLE.contains(&name.as_str()) {
+                    return Reply::Error(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                        name
+                    ));
+                }
+                if let Err(e) = updated.set(&name, &value) {
+                    return Reply::Error(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, e
+                    ));
+                }
             }
 
-            "GET" => {
-                if !check_authenticated(&mut writer, authendificated) {
-                    continue;
+            // Certificates are read again even if only their path was set
+            // again, so renewed files can be picked up without a restart
+            let tls_changed = parts[2..]
+                .chunks(2)
+                .any(|pair| pair[0].to_ascii_lowercase().starts_with(b"tls-"));
+            if tls_changed && updated.tls_port != 0 {
+                match tls::load(&updated) {
+                    Ok(tls) => *server.tls.write().unwrap() = Some(tls),
+                    Err(e) => return Reply::Error(format!("ERR CONFIG SET failed - {}", e)),
                 }
-                let store = store.read().unwrap();
-                handle_get(&parts, &store, &mut writer);
+                log(LogLevel::Notice, "TLS certificates reloaded");
             }
 
-            "TTL" => {
-                if !check_authenticated(&mut writer, authendificated) {
-                    continue;
-                }
-                let store = store.read().unwrap();
-                handle_ttl(&parts, &store, &mut writer);
+            logger::set_level(updated.loglevel);
+            server.aof.set_fsync(updated.appendfsync);
+            if updated.requirepass != config.requirepass {
+                server
+                    .acl
+                    .write()
+                    .unwrap()
+                    .set_default_password(&updated.requirepass);
             }
+            *config = updated;
+            Reply::ok()
+        }
 
-            "SET" => {
-                if !check_authenticated(&mut writer, authendificated) {
-                    continue;
-                }
-                let mut store = store.write().unwrap();
-                handle_set(&parts, &mut store, &mut writer);
+        "REWRITE" if parts.len() == 2 => match config.read().unwrap().rewrite() {
+            Ok(()) => {
+                log(LogLevel::Notice, "CONFIG REWRITE executed with success.");
+                Reply::ok()
             }
+            Err(e) => Reply::Error(format!("ERR Rewriting config file: {}", e)),
+        },
 
-            "DEL" => {
-                if !check_authenticated(&mut writer, authendificated) {
-                    continue;
-                }
-                let mut store = store.write().unwrap();
-                handle_del(&parts, &mut store, &mut writer);
+        _ => Reply::Error(format!(
+            "ERR unknown subcommand or wrong number of arguments for 'CONFIG {}'",
+            subcommand
+        )),
+    }
+}
+
+/**
+ * Handle SHUTDOWN request: `SHUTDOWN [SAVE|NOSAVE]`. As in Redis, there is no
+ * reply on success, the connection is just closed.
+ */
+fn handle_shutdown(parts: &[Vec<u8>], session: &mut Session, server: &Server) -> Option<Reply> {
+    let save = match parts.get(1).map(|arg| arg.to_ascii_uppercase()) {
+        None => ShutdownSave::Default,
+        Some(arg) if arg == b"SAVE" => ShutdownSave::Save,
+        Some(arg) if arg == b"NOSAVE" => ShutdownSave::NoSave,
+        Some(_) => return Some(Reply::error("ERR syntax error")),
+    };
+
+    log(LogLevel::Warning, "User requested shutdown...");
+    match shutdown(server, save) {
+        Ok(()) => {
+            session.closing = true;
+            None
+        }
+        Err(_) => Some(Reply::error("ERR Errors trying to SHUTDOWN. Check logs.")),
+    }
+}
+
+/**
+ * Handle ACL request: `ACL WHOAMI`, `ACL LIST`, `ACL USERS`, `ACL GETUSER <username>`,
+ * `ACL SETUSER <username> [<rule> ...]`, `ACL DELUSER <username> [<username> ...]`,
+ * `ACL LOAD` and `ACL SAVE`
+ */
+fn handle_acl(parts: &[Vec<u8>], session: &Session, server: &Server) -> Reply {
+    let subcommand = String::from_utf8_lossy(&parts[1]).to_uppercase();
+    let args: Vec<String> = parts[2..]
+        .iter()
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+
+    match subcommand.as_str() {
+        "WHOAMI" if args.is_empty() => Reply::bulk(session.user.as_deref().unwrap_or_default()),
+
+        "LIST" if args.is_empty() => Reply::Array(
+            server
+                .acl
+                .read()
+                .unwrap()
+                .users()
+                .map(|user| Reply::bulk(&format!("user {} {}", user.name, user.describe())))
+                .collect(),
+        ),
+
+        "USERS" if args.is_empty() => Reply::Array(
+            server
+                .acl
+                .read()
+                .unwrap()
+                .users()
+                .map(|user| Reply::bulk(&user.name))
+                .collect(),
+        ),
+
+        "GETUSER" if args.len() == 1 => match server.acl.read().unwrap().user(&args[0]) {
+            Some(user) => Reply::Map(vec![
+                (
+                    Reply::bulk("flags"),
+                    Reply::Array(user.flags().iter().map(|flag| Reply::bulk(flag)).collect()),
+                ),
+                (
+                    Reply::bulk("passwords"),
+                    Reply::Array(
+                        user.passwords()
+                            .map(|hash| Reply::bulk(&hash.to_string()))
+                            .collect(),
+                    ),
+                ),
+                (Reply::bulk("commands"), Reply::bulk(&user.command_rules())),
+                (Reply::bulk("keys"), Reply::bulk(&user.key_rules())),
+            ]),
+            None => Reply::Null,
+        },
+
+        "SETUSER" if !args.is_empty() => {
+            let rules: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+            match server.acl.write().unwrap().set_user(&args[0], &rules) {
+                Ok(()) => Reply::ok(),
+                Err(e) => Reply::Error(format!("ERR {}", e)),
             }
+        }
 
-            "QUIT" => {
-                writeln!(writer, "+OK").ok();
-                return;
+        "DELUSER" if !args.is_empty() => match server.acl.write().unwrap().delete_users(&args) {
+            Ok(deleted) => Reply::Integer(deleted as i64),
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        },
+
+        "LOAD" | "SAVE" if args.is_empty() => {
+            let (path, requirepass) = {
+                let config = server.config.read().unwrap();
+                (config.acl_path(), config.requirepass.clone())
+            };
+            let Some(path) = path else {
+                return Reply::error(
+                    "ERR This server is not configured with an ACL file. You may need to specify the 'aclfile' setting.",
+                );
+            };
+
+            let result = if subcommand == "LOAD" {
+                // Build the new user table completely before replacing the
+                // current one, so a broken file changes nothing
+                let mut loaded = Acl::new(&requirepass);
+                loaded
+                    .load(&path)
+                    .map(|()| *server.acl.write().unwrap() = loaded)
+            } else {
+                server.acl.read().unwrap().save(&path)
+            };
+            match result {
+                Ok(()) => Reply::ok(),
+                Err(e) => Reply::Error(format!("ERR {}", e)),
             }
+        }
+
+        _ => Reply::Error(format!(
+            "ERR unknown subcommand or wrong number of arguments for 'ACL {}'",
+            subcommand
+        )),
+    }
+}
 
-            _ => {
-                writeln!(writer, "-ERR: unknown command '{}'", &command).ok();
+/**
+ * Smallest and largest number of parts, including the command name, that a
+ * request for `command` may have. Commands that take a variable number of
+ * options check those themselves.
+ */
+fn arity(command: &str) -> RangeInclusive<usize> {
+    match command {
+        "GET" | "TTL" | "PTTL" | "PERSIST" | "EXPIRETIME" | "PEXPIRETIME" => 2..=2,
+        "STRLEN" | "GETDEL" => 2..=2,
+        "APPEND" => 3..=3,
+        "GETRANGE" | "SETRANGE" => 4..=4,
+        "AUTH" => 2..=3,
+        "DEL" | "CONFIG" | "ACL" | "GETEX" => 2..=usize::MAX,
+        "SET" | "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => 3..=usize::MAX,
+        "PING" | "SHUTDOWN" => 1..=2,
+        "SAVE" | "BGSAVE" | "BGREWRITEAOF" | "QUIT" => 1..=1,
+        _ => 1..=usize::MAX,
+    }
+}
+
+fn check_params(parts: &[Vec<u8>], command: &str) -> Result<(), Reply> {
+    if !arity(command).contains(&parts.len()) {
+        return Err(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}'",
+            command
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * Per-connection state
+ */
+struct Session {
+    /// User the connection is logged in as, `None` until it authenticated
+    user: Option<String>,
+    protocol: Protocol,
+    closing: bool,
+}
+
+/**
+ * Run a single request against the store and produce its reply, `None` if the
+ * request gets none (a successful SHUTDOWN)
+ */
+fn execute(parts: &[Vec<u8>], session: &mut Session, server: &Server) -> Option<Reply> {
+    let store = &server.store;
+    let config = &server.config;
+    let command = String::from_utf8_lossy(&parts[0]).to_uppercase();
+
+    // Nothing may run once the data is being saved for shutdown
+    if server.shutting_down.load(Ordering::SeqCst) {
+        return Some(Reply::error("ERR The server is shutting down"));
+    }
+
+    let (maxmemory, max_len) = {
+        let config = config.read().unwrap();
+        (config.maxmemory, config.proto_max_bulk_len)
+    };
+
+    // The user is looked up again for every request, so changed permissions
+    // apply right away and a deleted user loses access
+    let user = {
+        let acl = server.acl.read().unwrap();
+        if session.user.is_none()
+            && acl
+                .user(acl::DEFAULT_USER)
+                .is_some_and(|user| user.needs_no_auth())
+        {
+            session.user = Some(acl::DEFAULT_USER.to_string());
+        }
+        session
+            .user
+            .as_deref()
+            .and_then(|name| acl.user(name))
+            .cloned()
+    };
+
+    // AUTH, HELLO, PING and QUIT are always allowed, so a client can log in
+    let public = matches!(command.as_str(), "AUTH" | "HELLO" | "PING" | "QUIT");
+    if user.is_none() {
+        session.user = None;
+        if !public {
+            return Some(Reply::error("NOAUTH Authentication required."));
+        }
+    }
+
+    if let Err(reply) = check_params(parts, &command) {
+        return Some(reply);
+    }
+
+    if let Some(user) = user.filter(|_| !public) {
+        match user.check(&command.to_lowercase(), parts) {
+            Ok(()) => {}
+            Err(Denied::Command) => {
+                return Some(Reply::Error(format!(
+                    "NOPERM User {} has no permissions to run the '{}' command",
+                    user.name,
+                    command.to_lowercase()
+                )))
             }
+            Err(Denied::Key) => return Some(Reply::error("NOPERM No permissions to access a key")),
+        }
+    }
+
+    if maxmemory > 0 && DENY_OOM.contains(&command.as_str()) && store.used_memory() >= maxmemory {
+        return Some(Reply::error(
+            "OOM command not allowed when used memory > 'maxmemory'.",
+        ));
+    }
+
+    // Single-key commands only lock the shard holding their key
+    let key = || key_from_request(parts);
+    let reply = match command.as_str() {
+        "AUTH" => handle_auth(parts, session, &server.acl.read().unwrap()),
+        "HELLO" => handle_hello(parts, session, &server.acl.read().unwrap()),
+        "CONFIG" => handle_config(parts, server),
+        "ACL" => handle_acl(parts, session, server),
+        "SHUTDOWN" => return handle_shutdown(parts, session, server),
+        "SAVE" => handle_save(server),
+        "BGSAVE" => handle_bgsave(server),
+        "BGREWRITEAOF" => handle_bgrewriteaof(server),
+        "PING" => match parts.get(1) {
+            Some(message) => Reply::Bulk(message.clone()),
+            None => Reply::Simple("PONG".to_string()),
+        },
+        "GET" => handle_get(parts, &store.read(&key())),
+        "TTL" => handle_ttl(parts, &store.read(&key())),
+        "PTTL" => handle_pttl(parts, &store.read(&key())),
+        "SET" => handle_set(parts, &mut store.write(&key()), &server.aof),
+        "DEL" => handle_del(parts, store, &server.aof),
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+            handle_expire(parts, &mut store.write(&key()), &server.aof, &command)
         }
+        "PERSIST" => handle_persist(parts, &mut store.write(&key()), &server.aof),
+        "EXPIRETIME" => handle_expiretime(parts, &store.read(&key()), false),
+        "PEXPIRETIME" => handle_expiretime(parts, &store.read(&key()), true),
+        "APPEND" => handle_append(parts, &mut store.write(&key()), &server.aof, max_len),
+        "STRLEN" => handle_strlen(parts, &store.read(&key())),
+        "GETRANGE" => handle_getrange(parts, &store.read(&key())),
+        "SETRANGE" => handle_setrange(parts, &mut store.write(&key()), &server.aof, max_len),
+        "GETDEL" => handle_getdel(parts, &mut store.write(&key()), &server.aof),
+        "GETEX" => handle_getex(parts, &mut store.write(&key()), &server.aof),
+        "QUIT" => {
+            session.closing = true;
+            Reply::ok()
+        }
+        _ => Reply::Error(format!("ERR unknown command '{}'", &command)),
+    };
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::PasswordHash;
+    use crate::testing::{self, args, entry, TempFile};
+
+    fn set(shard: &mut Shard, words: &[&str]) -> Reply {
+        handle_set(&args(words), shard, &Aof::disabled())
+    }
+
+    fn expire(shard: &mut Shard, words: &[&str]) -> Reply {
+        let command = words[0].to_uppercase();
+        handle_expire(&args(words), shard, &Aof::disabled(), &command)
+    }
+
+    fn getrange(shard: &Shard, start: &str, end: &str) -> Reply {
+        handle_getrange(&args(&["GETRANGE", "k", start, end]), shard)
+    }
+
+    fn value(shard: &Shard, key: &str) -> Option<Vec<u8>> {
+        get_by_key(shard, key.as_bytes()).map(|entry| entry.value.clone())
+    }
+
+    fn ttl(shard: &Shard, key: &str) -> i64 {
+        ttl_millis(shard, key.as_bytes())
+    }
+
+    fn invalid_expire_time(command: &str) -> Reply {
+        Reply::Error(format!("ERR invalid expire time in '{}' command", command))
+    }
+
+    #[test]
+    fn set_accepts_the_legacy_ttl_form() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        assert_eq!(set(&mut shard, &["SET", "k", "10", "v"]), Reply::ok());
+        assert_eq!(value(&shard, "k"), Some(b"v".to_vec()));
+        assert!((9_900..=10_000).contains(&ttl(&shard, "k")));
+
+        // A TTL of 0 means no expiry
+        assert_eq!(set(&mut shard, &["SET", "k", "0", "v"]), Reply::ok());
+        assert_eq!(ttl(&shard, "k"), -1);
+
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "9223372036854775807", "v"]),
+            invalid_expire_time("set")
+        );
+    }
+
+    #[test]
+    fn changes_that_cannot_be_logged_are_not_applied() {
+        let aof = Aof::open(Path::new("/dev/full"), config::FsyncPolicy::Always).unwrap();
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        shard.insert(b"k".to_vec(), entry(b"v", None));
+
+        let refused = |reply: Reply| matches!(reply, Reply::Error(e) if e.starts_with("MISCONF "));
+        assert!(refused(handle_set(
+            &args(&["SET", "k", "new"]),
+            &mut shard,
+            &aof
+        )));
+        assert!(refused(handle_append(
+            &args(&["APPEND", "k", "x"]),
+            &mut shard,
+            &aof,
+            100
+        )));
+        assert!(refused(handle_getdel(
+            &args(&["GETDEL", "k"]),
+            &mut shard,
+            &aof
+        )));
+        assert!(refused(handle_expire(
+            &args(&["EXPIRE", "k", "10"]),
+            &mut shard,
+            &aof,
+            "EXPIRE"
+        )));
+        assert_eq!(value(&shard, "k"), Some(b"v".to_vec()));
+        assert_eq!(ttl(&shard, "k"), -1);
+    }
+
+    #[test]
+    fn set_reads_a_keyword_after_a_number_as_an_option() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        // `10` is the value here, not a TTL
+        assert_eq!(set(&mut shard, &["SET", "k", "10", "NX"]), Reply::ok());
+        assert_eq!(value(&shard, "k"), Some(b"10".to_vec()));
+        assert_eq!(ttl(&shard, "k"), -1);
+        assert_eq!(set(&mut shard, &["SET", "k", "20", "nx"]), Reply::Null);
+        assert_eq!(value(&shard, "k"), Some(b"10".to_vec()));
+    }
+
+    #[test]
+    fn set_with_nx_and_xx_depends_on_the_key() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        assert_eq!(set(&mut shard, &["SET", "k", "v", "XX"]), Reply::Null);
+        assert_eq!(value(&shard, "k"), None);
+        assert_eq!(set(&mut shard, &["SET", "k", "v", "NX"]), Reply::ok());
+        assert_eq!(set(&mut shard, &["SET", "k", "w", "NX"]), Reply::Null);
+        assert_eq!(value(&shard, "k"), Some(b"v".to_vec()));
+        assert_eq!(set(&mut shard, &["SET", "k", "w", "XX"]), Reply::ok());
+        assert_eq!(value(&shard, "k"), Some(b"w".to_vec()));
+
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "v", "NX", "XX"]),
+            Reply::error("ERR syntax error")
+        );
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "v", "XX", "NX"]),
+            Reply::error("ERR syntax error")
+        );
+    }
+
+    #[test]
+    fn set_with_get_returns_the_previous_value() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        assert_eq!(set(&mut shard, &["SET", "k", "v", "GET"]), Reply::Null);
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "w", "GET"]),
+            Reply::Bulk(b"v".to_vec())
+        );
+        assert_eq!(value(&shard, "k"), Some(b"w".to_vec()));
+
+        // With NX the old value is returned and left in place
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "x", "NX", "GET"]),
+            Reply::Bulk(b"w".to_vec())
+        );
+        assert_eq!(value(&shard, "k"), Some(b"w".to_vec()));
+
+        shard.remove(b"k");
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "x", "GET", "XX"]),
+            Reply::Null
+        );
+        assert_eq!(value(&shard, "k"), None);
+    }
+
+    #[test]
+    fn set_sets_or_keeps_the_expiry() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "v", "PX", "5000"]),
+            Reply::ok()
+        );
+        assert!((4_900..=5_000).contains(&ttl(&shard, "k")));
+        assert_eq!(set(&mut shard, &["SET", "k", "w", "KEEPTTL"]), Reply::ok());
+        assert!((4_900..=5_000).contains(&ttl(&shard, "k")));
+        assert_eq!(value(&shard, "k"), Some(b"w".to_vec()));
+
+        // Without KEEPTTL a plain SET removes the expiry
+        assert_eq!(set(&mut shard, &["SET", "k", "v"]), Reply::ok());
+        assert_eq!(ttl(&shard, "k"), -1);
+
+        assert_eq!(set(&mut shard, &["SET", "k", "v", "ex", "10"]), Reply::ok());
+        assert!((9_900..=10_000).contains(&ttl(&shard, "k")));
+
+        let at = store::to_unix_millis(Instant::now()) + 20_000;
+        let at = at.to_string();
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "v", "PXAT", &at]),
+            Reply::ok()
+        );
+        assert!((19_900..=20_000).contains(&ttl(&shard, "k")));
+    }
+
+    #[test]
+    fn set_with_an_absolute_time_in_the_past_leaves_no_key() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "v", "EXAT", "1"]),
+            Reply::ok()
+        );
+        assert_eq!(value(&shard, "k"), None);
+        assert_eq!(ttl(&shard, "k"), -2);
+    }
+
+    #[test]
+    fn set_rejects_conflicting_and_repeated_options() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        for words in [
+            &["SET", "k", "v", "KEEPTTL", "EX", "10"][..],
+            &["SET", "k", "v", "EX", "10", "KEEPTTL"],
+            &["SET", "k", "v", "EX", "10", "PX", "100"],
+            &["SET", "k", "v", "EX", "10", "EX", "10"],
+            &["SET", "k", "v", "EX"],
+            &["SET", "k", "v", "IFEQ", "x"],
+        ] {
+            assert_eq!(
+                set(&mut shard, words),
+                Reply::error("ERR syntax error"),
+                "{:?}",
+                words
+            );
+        }
+        assert_eq!(value(&shard, "k"), None);
+    }
+
+    #[test]
+    fn set_rejects_invalid_and_overflowing_times() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        for words in [
+            &["SET", "k", "v", "EX", "0"][..],
+            &["SET", "k", "v", "PX", "-1"],
+            &["SET", "k", "v", "EXAT", "0"],
+            &["SET", "k", "v", "EX", "9223372036854775807"],
+            &["SET", "k", "v", "EXAT", "9223372036854775807"],
+            &["SET", "k", "v", "PX", "9223372036854775807"],
+        ] {
+            assert_eq!(
+                set(&mut shard, words),
+                invalid_expire_time("set"),
+                "{:?}",
+                words
+            );
+        }
+        assert_eq!(
+            set(&mut shard, &["SET", "k", "v", "EX", "soon"]),
+            Reply::error("ERR value is not an integer or out of range")
+        );
+        assert_eq!(value(&shard, "k"), None);
+    }
+
+    #[test]
+    fn set_keeps_a_pxat_far_in_the_future() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        // Absolute times are stored as given, there is nothing to overflow
+        assert_eq!(
+            set(
+                &mut shard,
+                &["SET", "k", "v", "PXAT", "9223372036854775807"]
+            ),
+            Reply::ok()
+        );
+        assert!(ttl(&shard, "k") > 0);
+    }
+
+    #[test]
+    fn expire_sets_relative_and_absolute_times() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "10"]),
+            Reply::Integer(0)
+        );
+
+        set(&mut shard, &["SET", "k", "v"]);
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "10"]),
+            Reply::Integer(1)
+        );
+        assert!((9_900..=10_000).contains(&ttl(&shard, "k")));
+        assert_eq!(
+            expire(&mut shard, &["PEXPIRE", "k", "500"]),
+            Reply::Integer(1)
+        );
+        assert!((400..=500).contains(&ttl(&shard, "k")));
+
+        let now = store::to_unix_millis(Instant::now());
+        let at = (now / 1000 + 30).to_string();
+        assert_eq!(
+            expire(&mut shard, &["EXPIREAT", "k", &at]),
+            Reply::Integer(1)
+        );
+        assert!((28_000..=30_000).contains(&ttl(&shard, "k")));
+        let at = (now + 40_000).to_string();
+        assert_eq!(
+            expire(&mut shard, &["PEXPIREAT", "k", &at]),
+            Reply::Integer(1)
+        );
+        assert!((39_900..=40_000).contains(&ttl(&shard, "k")));
+    }
+
+    #[test]
+    fn expire_nx_and_xx_depend_on_an_existing_expiry() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        set(&mut shard, &["SET", "k", "v"]);
+
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "10", "XX"]),
+            Reply::Integer(0)
+        );
+        assert_eq!(ttl(&shard, "k"), -1);
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "10", "NX"]),
+            Reply::Integer(1)
+        );
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "20", "nx"]),
+            Reply::Integer(0)
+        );
+        assert!(ttl(&shard, "k") <= 10_000);
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "20", "XX"]),
+            Reply::Integer(1)
+        );
+        assert!(ttl(&shard, "k") > 10_000);
+    }
+
+    #[test]
+    fn expire_gt_and_lt_compare_with_the_current_expiry() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        set(&mut shard, &["SET", "k", "v", "EX", "100"]);
+
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "50", "GT"]),
+            Reply::Integer(0)
+        );
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "200", "GT"]),
+            Reply::Integer(1)
+        );
+        assert!(ttl(&shard, "k") > 100_000);
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "300", "LT"]),
+            Reply::Integer(0)
+        );
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "50", "LT"]),
+            Reply::Integer(1)
+        );
+        assert!(ttl(&shard, "k") <= 50_000);
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "60", "XX", "GT"]),
+            Reply::Integer(1)
+        );
+    }
+
+    #[test]
+    fn expire_counts_a_key_without_expiry_as_infinite() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        set(&mut shard, &["SET", "k", "v"]);
+
+        // Nothing is greater than forever, anything is less
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "10", "GT"]),
+            Reply::Integer(0)
+        );
+        assert_eq!(ttl(&shard, "k"), -1);
+        assert_eq!(
+            expire(&mut shard, &["EXPIRE", "k", "10", "LT"]),
+            Reply::Integer(1)
+        );
+        assert!((9_900..=10_000).contains(&ttl(&shard, "k")));
+    }
+
+    #[test]
+    fn expire_with_a_past_time_deletes_the_key() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+
+        for words in [
+            &["EXPIRE", "k", "-1"][..],
+            &["PEXPIRE", "k", "0"],
+            &["EXPIREAT", "k", "1"],
+            &["PEXPIREAT", "k", "1"],
+        ] {
+            set(&mut shard, &["SET", "k", "v"]);
+            assert_eq!(expire(&mut shard, words), Reply::Integer(1), "{:?}", words);
+            assert!(shard.get(b"k").is_none(), "{:?}", words);
+        }
+    }
+
+    #[test]
+    fn expire_rejects_invalid_options_and_overflowing_times() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        set(&mut shard, &["SET", "k", "v"]);
+
+        for (words, error) in [
+            (
+                &["EXPIRE", "k", "10", "NX", "XX"][..],
+                "ERR NX and XX, GT or LT options at the same time are not compatible",
+            ),
+            (
+                &["EXPIRE", "k", "10", "GT", "NX"],
+                "ERR NX and XX, GT or LT options at the same time are not compatible",
+            ),
+            (
+                &["EXPIRE", "k", "10", "GT", "LT"],
+                "ERR GT and LT options at the same time are not compatible",
+            ),
+            (
+                &["EXPIRE", "k", "10", "ALWAYS"],
+                "ERR Unsupported option ALWAYS",
+            ),
+            (
+                &["EXPIRE", "k", "ten"],
+                "ERR value is not an integer or out of range",
+            ),
+        ] {
+            assert_eq!(
+                expire(&mut shard, words),
+                Reply::error(error),
+                "{:?}",
+                words
+            );
+        }
+
+        for words in [
+            &["EXPIRE", "k", "9223372036854775807"][..],
+            &["PEXPIRE", "k", "9223372036854775807"],
+            &["EXPIREAT", "k", "9223372036854775807"],
+        ] {
+            let command = words[0].to_lowercase();
+            assert_eq!(
+                expire(&mut shard, words),
+                invalid_expire_time(&command),
+                "{:?}",
+                words
+            );
+        }
+        assert_eq!(ttl(&shard, "k"), -1);
+    }
+
+    #[test]
+    fn append_creates_or_extends_the_value() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        let aof = Aof::disabled();
+        let append = |shard: &mut Shard, data: &str| {
+            handle_append(&args(&["APPEND", "k", data]), shard, &aof, 8)
+        };
+
+        assert_eq!(append(&mut shard, "abc"), Reply::Integer(3));
+        assert_eq!(append(&mut shard, "de"), Reply::Integer(5));
+        assert_eq!(value(&shard, "k"), Some(b"abcde".to_vec()));
+        assert_eq!(
+            handle_strlen(&args(&["STRLEN", "k"]), &shard),
+            Reply::Integer(5)
+        );
+
+        // The result may not outgrow proto-max-bulk-len
+        assert_eq!(
+            append(&mut shard, "fghi"),
+            Reply::error("ERR string exceeds maximum allowed size (proto-max-bulk-len)")
+        );
+        assert_eq!(append(&mut shard, "fgh"), Reply::Integer(8));
+
+        // An expired value is not appended to
+        let past = Instant::now() - Duration::from_millis(1);
+        shard.insert(b"k".to_vec(), entry(b"stale", Some(past)));
+        assert_eq!(append(&mut shard, "new"), Reply::Integer(3));
+        assert_eq!(ttl(&shard, "k"), -1);
+    }
+
+    #[test]
+    fn getrange_clamps_offsets_to_the_value() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        let bulk = |value: &str| Reply::Bulk(value.as_bytes().to_vec());
+
+        assert_eq!(getrange(&shard, "0", "-1"), bulk(""));
+        set(&mut shard, &["SET", "k", "Hello World"]);
+
+        assert_eq!(getrange(&shard, "0", "4"), bulk("Hello"));
+        assert_eq!(getrange(&shard, "-5", "-1"), bulk("World"));
+        assert_eq!(getrange(&shard, "0", "-1"), bulk("Hello World"));
+        assert_eq!(getrange(&shard, "-100", "2"), bulk("Hel"));
+        assert_eq!(getrange(&shard, "6", "100"), bulk("World"));
+        assert_eq!(getrange(&shard, "0", "-100"), bulk("H"));
+        assert_eq!(getrange(&shard, "100", "200"), bulk(""));
+        assert_eq!(getrange(&shard, "3", "1"), bulk(""));
+        assert_eq!(
+            getrange(&shard, "0", "end"),
+            Reply::error("ERR value is not an integer or out of range")
+        );
+
+        set(&mut shard, &["SET", "k", ""]);
+        assert_eq!(getrange(&shard, "0", "-1"), bulk(""));
+    }
+
+    #[test]
+    fn setrange_pads_with_zero_bytes() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        let aof = Aof::disabled();
+        let setrange = |shard: &mut Shard, offset: &str, data: &str| {
+            handle_setrange(&args(&["SETRANGE", "k", offset, data]), shard, &aof, 16)
+        };
+
+        // Empty data on a missing key changes nothing
+        assert_eq!(setrange(&mut shard, "5", ""), Reply::Integer(0));
+        assert!(shard.get(b"k").is_none());
+
+        assert_eq!(setrange(&mut shard, "3", "ab"), Reply::Integer(5));
+        assert_eq!(value(&shard, "k"), Some(b"\0\0\0ab".to_vec()));
+        assert_eq!(setrange(&mut shard, "1", "xyz"), Reply::Integer(5));
+        assert_eq!(value(&shard, "k"), Some(b"\0xyzb".to_vec()));
+        assert_eq!(setrange(&mut shard, "7", "!"), Reply::Integer(8));
+        assert_eq!(value(&shard, "k"), Some(b"\0xyzb\0\0!".to_vec()));
+        assert_eq!(setrange(&mut shard, "2", ""), Reply::Integer(8));
+
+        assert_eq!(
+            setrange(&mut shard, "-1", "x"),
+            Reply::error("ERR offset is out of range")
+        );
+        assert_eq!(
+            setrange(&mut shard, "15", "ab"),
+            Reply::error("ERR string exceeds maximum allowed size (proto-max-bulk-len)")
+        );
+        assert_eq!(setrange(&mut shard, "15", "a"), Reply::Integer(16));
+    }
+
+    #[test]
+    fn getdel_returns_and_removes_the_value() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        let aof = Aof::disabled();
+        let getdel = |shard: &mut Shard| handle_getdel(&args(&["GETDEL", "k"]), shard, &aof);
+
+        assert_eq!(getdel(&mut shard), Reply::Null);
+        set(&mut shard, &["SET", "k", "v"]);
+        assert_eq!(getdel(&mut shard), Reply::Bulk(b"v".to_vec()));
+        assert!(shard.get(b"k").is_none());
+    }
+
+    #[test]
+    fn getex_returns_the_value_and_changes_its_expiry() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        let aof = Aof::disabled();
+        let getex = |shard: &mut Shard, words: &[&str]| handle_getex(&args(words), shard, &aof);
+        let v = Reply::Bulk(b"v".to_vec());
+
+        assert_eq!(getex(&mut shard, &["GETEX", "k", "EX", "10"]), Reply::Null);
+        assert!(shard.get(b"k").is_none());
+
+        set(&mut shard, &["SET", "k", "v"]);
+        assert_eq!(getex(&mut shard, &["GETEX", "k"]), v);
+        assert_eq!(ttl(&shard, "k"), -1);
+        assert_eq!(getex(&mut shard, &["GETEX", "k", "px", "5000"]), v);
+        assert!((4_900..=5_000).contains(&ttl(&shard, "k")));
+        assert_eq!(getex(&mut shard, &["GETEX", "k"]), v);
+        assert!(ttl(&shard, "k") > 0);
+        assert_eq!(getex(&mut shard, &["GETEX", "k", "PERSIST"]), v);
+        assert_eq!(ttl(&shard, "k"), -1);
+
+        for words in [
+            &["GETEX", "k", "EX"][..],
+            &["GETEX", "k", "PERSIST", "EX", "10"],
+            &["GETEX", "k", "EX", "10", "PX", "10"],
+            &["GETEX", "k", "KEEPTTL"],
+        ] {
+            assert_eq!(
+                getex(&mut shard, words),
+                Reply::error("ERR syntax error"),
+                "{:?}",
+                words
+            );
+        }
+        assert_eq!(
+            getex(&mut shard, &["GETEX", "k", "EX", "0"]),
+            invalid_expire_time("getex")
+        );
+
+        // An absolute time in the past deletes the key
+        assert_eq!(getex(&mut shard, &["GETEX", "k", "EXAT", "1"]), v);
+        assert!(shard.get(b"k").is_none());
+    }
+
+    #[test]
+    fn extra_arguments_are_refused() {
+        for words in [
+            &["GET", "a", "b"][..],
+            &["APPEND", "k", "v", "junk"],
+            &["GETRANGE", "k", "0", "1", "x"],
+            &["SETRANGE", "k", "0"],
+            &["STRLEN", "k", "x"],
+            &["AUTH", "a", "b", "c"],
+            &["PING", "a", "b"],
+            &["SAVE", "now"],
+            &["SET", "k"],
+            &["DEL"],
+        ] {
+            let command = words[0];
+            assert_eq!(
+                check_params(&args(words), command),
+                Err(Reply::Error(format!(
+                    "ERR wrong number of arguments for '{}'",
+                    command
+                ))),
+                "{:?}",
+                words
+            );
+        }
+
+        for words in [
+            &["GET", "a"][..],
+            &["APPEND", "k", "v"],
+            &["GETRANGE", "k", "0", "1"],
+            &["DEL", "a", "b", "c"],
+            &["SET", "k", "v", "NX", "GET", "EX", "10"],
+            &["PING"],
+            &["UNKNOWN", "a", "b"],
+        ] {
+            assert_eq!(check_params(&args(words), words[0]), Ok(()), "{:?}", words);
+        }
+    }
+
+    #[test]
+    fn del_counts_only_live_keys() {
+        let store = Store::new();
+        let past = Instant::now() - Duration::from_millis(1);
+        store.write(b"a").insert(b"a".to_vec(), entry(b"1", None));
+        store
+            .write(b"b")
+            .insert(b"b".to_vec(), entry(b"2", Some(past)));
+
+        let reply = handle_del(&args(&["DEL", "a", "b", "c"]), &store, &Aof::disabled());
+        assert_eq!(reply, Reply::Integer(1));
+        assert!(store.read(b"a").get(b"a").is_none());
+    }
+
+    fn session(user: Option<&str>) -> Session {
+        Session {
+            user: user.map(str::to_string),
+            protocol: Protocol::Resp2,
+            closing: false,
+        }
+    }
+
+    #[test]
+    fn hello_switches_the_protocol_and_describes_the_server() {
+        let acl = Acl::new("");
+        let mut session = session(Some(acl::DEFAULT_USER));
+
+        let reply = handle_hello(&args(&["HELLO", "3"]), &mut session, &acl);
+        assert_eq!(session.protocol, Protocol::Resp3);
+        let Reply::Map(pairs) = &reply else {
+            panic!("HELLO replied {:?}", reply);
+        };
+        let fields: Vec<(Reply, Reply)> = [
+            ("server", Reply::bulk("keyvy")),
+            ("version", Reply::bulk(env!("CARGO_PKG_VERSION"))),
+            ("proto", Reply::Integer(3)),
+            ("mode", Reply::bulk("standalone")),
+            ("role", Reply::bulk("master")),
+            ("modules", Reply::Array(vec![])),
+        ]
+        .into_iter()
+        .map(|(name, value)| (Reply::bulk(name), value))
+        .collect();
+        assert_eq!(pairs, &fields);
+
+        // Without a version the protocol stays as it is
+        let reply = handle_hello(&args(&["HELLO"]), &mut session, &acl);
+        assert!(matches!(&reply, Reply::Map(pairs) if pairs[2].1 == Reply::Integer(3)));
+        handle_hello(&args(&["HELLO", "2", "SETNAME", "app"]), &mut session, &acl);
+        assert_eq!(session.protocol, Protocol::Resp2);
+    }
+
+    #[test]
+    fn hello_rejects_bad_versions_and_options() {
+        let acl = Acl::new("");
+        let mut session = session(Some(acl::DEFAULT_USER));
+
+        for (words, error) in [
+            (&["HELLO", "4"][..], "NOPROTO unsupported protocol version"),
+            (
+                &["HELLO", "three"],
+                "ERR Protocol version is not an integer or out of range",
+            ),
+            (
+                &["HELLO", "3", "SETNAME"],
+                "ERR Syntax error in HELLO option 'SETNAME'",
+            ),
+            (
+                &["HELLO", "3", "AUTH", "default"],
+                "ERR Syntax error in HELLO option 'AUTH'",
+            ),
+        ] {
+            let reply = handle_hello(&args(words), &mut session, &acl);
+            assert_eq!(reply, Reply::error(error), "{:?}", words);
+            assert_eq!(session.protocol, Protocol::Resp2, "{:?}", words);
+        }
+    }
+
+    #[test]
+    fn hello_can_authenticate() {
+        let acl = Acl::new(&PasswordHash::new(b"secret").to_string());
+        let mut session = session(None);
+
+        let reply = handle_hello(&args(&["HELLO", "3"]), &mut session, &acl);
+        assert!(matches!(reply, Reply::Error(e) if e.starts_with("NOAUTH")));
+        let reply = handle_hello(
+            &args(&["HELLO", "3", "AUTH", "default", "wrong"]),
+            &mut session,
+            &acl,
+        );
+        assert!(matches!(reply, Reply::Error(e) if e.starts_with("WRONGPASS")));
+        assert_eq!(session.user, None);
+        assert_eq!(session.protocol, Protocol::Resp2);
+
+        let reply = handle_hello(
+            &args(&["HELLO", "3", "AUTH", "default", "secret"]),
+            &mut session,
+            &acl,
+        );
+        assert!(matches!(reply, Reply::Map(_)));
+        assert_eq!(session.user.as_deref(), Some(acl::DEFAULT_USER));
+        assert_eq!(session.protocol, Protocol::Resp3);
+    }
+
+    /**
+     * A server that writes its snapshot to `snapshot`
+     */
+    fn server_saving_to(snapshot: &Path) -> Arc<Server> {
+        testing::server(Config {
+            dir: snapshot.parent().unwrap().to_path_buf(),
+            dbfilename: snapshot.file_name().unwrap().to_str().unwrap().to_string(),
+            ..Config::default()
+        })
+    }
+
+    fn run(server: &Server, session: &mut Session, words: &[&str]) -> Option<Reply> {
+        execute(&args(words), session, server)
+    }
+
+    #[test]
+    fn shutdown_saves_a_snapshot_unless_told_not_to() {
+        let snapshot = TempFile::reserve("shutdown.kvy");
+        let server = server_saving_to(snapshot.path());
+        server
+            .store
+            .write(b"k")
+            .insert(b"k".to_vec(), entry(b"v", None));
+        let store = server.store.read_all();
+
+        save_for_shutdown(&server, &store, ShutdownSave::NoSave).unwrap();
+        assert!(!snapshot.path().exists());
+
+        save_for_shutdown(&server, &store, ShutdownSave::Save).unwrap();
+        let loaded = snapshot::load(snapshot.path()).unwrap();
+        assert_eq!(value(&loaded.read(b"k"), "k"), Some(b"v".to_vec()));
+        fs::remove_file(snapshot.path()).unwrap();
+
+        // Without an append-only file the snapshot is all there is
+        save_for_shutdown(&server, &store, ShutdownSave::Default).unwrap();
+        assert!(snapshot.path().exists());
+    }
+
+    #[test]
+    fn failed_shutdown_leaves_the_server_running() {
+        let missing = std::env::temp_dir()
+            .join("keyvy-no-such-dir")
+            .join("dump.kvy");
+        let server = server_saving_to(&missing);
+        let mut session = session(None);
+
+        assert_eq!(
+            run(&server, &mut session, &["SHUTDOWN", "SAVE"]),
+            Some(Reply::error("ERR Errors trying to SHUTDOWN. Check logs."))
+        );
+        assert!(!session.closing);
+        assert!(!server.shutting_down.load(Ordering::SeqCst));
+        assert_eq!(
+            run(&server, &mut session, &["SET", "k", "v"]),
+            Some(Reply::ok())
+        );
+        assert_eq!(
+            run(&server, &mut session, &["GET", "k"]),
+            Some(Reply::bulk("v"))
+        );
+    }
+
+    #[test]
+    fn shutdown_closes_the_connection_without_a_reply() {
+        let snapshot = TempFile::reserve("nosave.kvy");
+        let server = server_saving_to(snapshot.path());
+        let mut client = session(None);
+
+        assert_eq!(run(&server, &mut client, &["SHUTDOWN", "NOSAVE"]), None);
+        assert!(client.closing);
+        assert!(!snapshot.path().exists());
+        assert_eq!(
+            run(&server, &mut session(None), &["PING"]),
+            Some(Reply::error("ERR The server is shutting down"))
+        );
     }
 }
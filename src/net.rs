@@ -0,0 +1,1170 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::event::Source;
+use mio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use socket2::{Domain, SockRef, TcpKeepalive, Type};
+
+use crate::acl;
+use crate::config::LogLevel;
+use crate::logger::log;
+use crate::resp::{Limits, Protocol, Reply, RequestReader};
+use crate::tls::TlsStream;
+use crate::{execute, Server, Session};
+
+/**
+ * Token of the waker that tells a worker new connections are waiting
+ */
+const WAKER: Token = Token(0);
+
+/**
+ * Token of the waker that tells the acceptor to stop
+ */
+const STOP: Token = Token(usize::MAX);
+
+/**
+ * How long workers keep trying to send pending replies when the server stops
+ */
+const STOP_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/**
+ * How long the acceptor waits for the workers to finish when the server stops
+ */
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/**
+ * How often workers look for connections that have been idle for too long
+ */
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+ * Requests a connection may run before the worker moves on to the others
+ */
+const MAX_REQUESTS_PER_EVENT: usize = 1000;
+
+/**
+ * Sent to clients from other hosts while protected mode is in effect
+ */
+const PROTECTED_MODE_ERROR: &[u8] =
+    b"-DENIED Running in protected mode because protected mode is enabled \
+and no password is set for the default user. In this mode connections are only accepted from the \
+loopback interface. Set a password with 'CONFIG SET requirepass <password>' from a local client, \
+or turn protected mode off with 'CONFIG SET protected-mode no'.\r\n";
+
+/**
+ * Set once the server is stopping, see `stop`
+ */
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Wakes the acceptor's event loop, so it notices `STOPPING`
+ */
+static STOP_WAKER: OnceLock<Waker> = OnceLock::new();
+
+/**
+ * Make `serve` return: listeners are closed, workers send the replies that
+ * are still pending and close their connections.
+ */
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+    if let Some(waker) = STOP_WAKER.get() {
+        waker.wake().ok();
+    }
+}
+
+/**
+ * What the acceptor needs to hand a connection over to a worker
+ */
+struct WorkerHandle {
+    sender: Sender<Stream>,
+    waker: Waker,
+}
+
+/**
+ * A socket clients connect to
+ */
+pub enum Listener {
+    Tcp {
+        socket: std::net::TcpListener,
+        /// Clients on this socket speak TLS
+        tls: bool,
+    },
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/**
+ * A listener registered with the acceptor's event loop
+ */
+enum Socket {
+    Tcp(TcpListener, bool),
+    Unix(UnixListener),
+}
+
+impl Socket {
+    /**
+     * Accept a client, along with its address if it connected over TCP
+     */
+    fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Socket::Tcp(listener, _) => listener
+                .accept()
+                .map(|(stream, address)| (Stream::Tcp(stream), Some(address))),
+            Socket::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _)| (Stream::Unix(stream), None)),
+        }
+    }
+
+    fn is_tls(&self) -> bool {
+        matches!(self, Socket::Tcp(_, true))
+    }
+}
+
+/**
+ * Serve clients connecting to any of `listeners`. Connections are spread over
+ * a fixed pool of `threads` workers, each running its own event loop, so the
+ * number of threads doesn't grow with the number of clients. Returns once
+ * `stop` was called, or on error.
+ */
+pub fn serve(listeners: Vec<Listener>, server: Arc<Server>, threads: usize) -> io::Result<()> {
+    let mut workers = Vec::with_capacity(threads);
+    let mut threads_running = Vec::with_capacity(threads);
+    for id in 0..threads {
+        let (worker, handle) = Worker::new(Arc::clone(&server))?;
+        let thread = thread::Builder::new()
+            .name(format!("io-{}", id))
+            .spawn(move || {
+                if let Err(e) = worker.run() {
+                    log(LogLevel::Warning, &format!("I/O thread stopped: {}", e));
+                }
+            })?;
+        workers.push(handle);
+        threads_running.push(thread);
+    }
+
+    // Each listener is registered with its index in `listeners` as token
+    let poll = Poll::new()?;
+    STOP_WAKER
+        .set(Waker::new(poll.registry(), STOP)?)
+        .map_err(|_| io::Error::other("the server is already running"))?;
+    let mut sockets = Vec::with_capacity(listeners.len());
+    for (index, listener) in listeners.into_iter().enumerate() {
+        let mut socket = match listener {
+            Listener::Tcp { socket, tls } => {
+                socket.set_nonblocking(true)?;
+                Socket::Tcp(TcpListener::from_std(socket), tls)
+            }
+            Listener::Unix(socket) => {
+                socket.set_nonblocking(true)?;
+                Socket::Unix(UnixListener::from_std(socket))
+            }
+        };
+        let registry = poll.registry();
+        match &mut socket {
+            Socket::Tcp(listener, _) => {
+                registry.register(listener, Token(index), Interest::READABLE)?
+            }
+            Socket::Unix(listener) => {
+                registry.register(listener, Token(index), Interest::READABLE)?
+            }
+        }
+        sockets.push(socket);
+    }
+
+    let mut acceptor = Acceptor {
+        poll,
+        server,
+        workers,
+        next_worker: 0,
+    };
+    let mut events = Events::with_capacity(64);
+    while !STOPPING.load(Ordering::SeqCst) {
+        if let Err(e) = acceptor.poll.poll(&mut events, None) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        for event in events.iter().filter(|event| event.token() != STOP) {
+            acceptor.accept(&sockets[event.token().0])?;
+        }
+    }
+
+    // No new connections from here on
+    drop(sockets);
+    for worker in &acceptor.workers {
+        worker.waker.wake().ok();
+    }
+    // A worker waiting for a lock the shutdown holds never finishes, so
+    // don't wait for them forever
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while Instant::now() < deadline && threads_running.iter().any(|thread| !thread.is_finished()) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+/**
+ * Accepts connections and hands them to the workers in turn
+ */
+struct Acceptor {
+    poll: Poll,
+    server: Arc<Server>,
+    workers: Vec<WorkerHandle>,
+    next_worker: usize,
+}
+
+impl Acceptor {
+    fn accept(&mut self, socket: &Socket) -> io::Result<()> {
+        // The listener is edge-triggered, so accept until it runs dry
+        loop {
+            match socket.accept() {
+                Ok((stream, peer)) => self.hand_over(stream, peer, socket.is_tls())?,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log(
+                        LogLevel::Warning,
+                        &format!("Error accepting connection: {}", e),
+                    );
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /**
+     * Set up a new connection and pass it to the next worker
+     */
+    fn hand_over(
+        &mut self,
+        mut stream: Stream,
+        address: Option<SocketAddr>,
+        tls: bool,
+    ) -> io::Result<()> {
+        let server = &self.server;
+        let peer = match address {
+            Some(address) => address.to_string(),
+            None => "unix socket".to_string(),
+        };
+        let (maxclients, keepalive, protected_mode) = {
+            let config = server.config.read().unwrap();
+            (
+                config.maxclients,
+                config.tcp_keepalive,
+                config.protected_mode,
+            )
+        };
+        if server.clients.load(Ordering::Relaxed) >= maxclients {
+            // Best effort, the socket is dropped right after anyway
+            stream
+                .write_all(b"-ERR max number of clients reached\r\n")
+                .ok();
+            log(
+                LogLevel::Verbose,
+                &format!("Rejected connection from {}: too many clients", peer),
+            );
+            return Ok(());
+        }
+        let has_password = !server
+            .acl
+            .read()
+            .unwrap()
+            .user(acl::DEFAULT_USER)
+            .is_some_and(|user| user.needs_no_auth());
+        if protected_mode_denies(protected_mode, address, has_password) {
+            stream.write_all(PROTECTED_MODE_ERROR).ok();
+            log(
+                LogLevel::Verbose,
+                &format!("Rejected connection from {}: protected mode", peer),
+            );
+            return Ok(());
+        }
+
+        let stream = match stream {
+            Stream::Tcp(stream) => {
+                if let Err(e) = set_keepalive(&stream, keepalive) {
+                    log(
+                        LogLevel::Warning,
+                        &format!("Can't set TCP keepalive for {}: {}", peer, e),
+                    );
+                }
+                if tls {
+                    // Certificates reloaded with CONFIG SET apply from the
+                    // next connection on
+                    let config = server.tls.read().unwrap().clone();
+                    let Some(config) = config else {
+                        return Ok(());
+                    };
+                    match TlsStream::new(stream, config) {
+                        Ok(stream) => Stream::Tls(Box::new(stream)),
+                        Err(e) => {
+                            log(
+                                LogLevel::Warning,
+                                &format!("Can't set up TLS for {}: {}", peer, e),
+                            );
+                            return Ok(());
+                        }
+                    }
+                } else {
+                    Stream::Tcp(stream)
+                }
+            }
+            stream => stream,
+        };
+
+        log(
+            LogLevel::Verbose,
+            &format!("Accepted connection from {}", peer),
+        );
+        self.server.clients.fetch_add(1, Ordering::Relaxed);
+        let worker = &self.workers[self.next_worker];
+        self.next_worker = (self.next_worker + 1) % self.workers.len();
+        if worker.sender.send(stream).is_ok() {
+            worker.waker.wake()?;
+        } else {
+            self.server.clients.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Whether protected mode turns away a client connecting from `peer`, `None`
+ * for the Unix socket. It only does while it is on and the `default` user has
+ * no password, and then only for clients outside the loopback interface.
+ */
+fn protected_mode_denies(
+    protected_mode: bool,
+    peer: Option<SocketAddr>,
+    has_password: bool,
+) -> bool {
+    protected_mode
+        && !has_password
+        && peer.is_some_and(|peer| !peer.ip().to_canonical().is_loopback())
+}
+
+/**
+ * Open a TCP listener. IPv6 listeners only take IPv6 clients, so `::` and
+ * `0.0.0.0` can be bound side by side on the same port.
+ */
+pub fn listen(address: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let bind = || {
+        let socket = socket2::Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+        if address.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(511)?;
+        Ok(socket.into())
+    };
+    bind().map_err(|e: io::Error| io::Error::new(e.kind(), format!("{}: {}", address, e)))
+}
+
+/**
+ * Probe idle connections every `seconds` so dead peers are noticed, as Redis
+ * does: the first probe after `seconds` of silence, then every third of that.
+ */
+fn set_keepalive(stream: &TcpStream, seconds: u64) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    if seconds == 0 {
+        return socket.set_keepalive(false);
+    }
+    let time = Duration::from_secs(seconds);
+    let interval = Duration::from_secs((seconds / 3).max(1));
+    socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time).with_interval(interval))
+}
+
+/**
+ * One thread of the pool: owns a set of connections and serves them from a
+ * single event loop.
+ */
+struct Worker {
+    poll: Poll,
+    incoming: Receiver<Stream>,
+    server: Arc<Server>,
+    connections: HashMap<Token, Connection>,
+    /// Connections that stopped at `MAX_REQUESTS_PER_EVENT` and get another
+    /// turn without waiting for an event
+    busy: Vec<Token>,
+    next_token: usize,
+    last_idle_check: Instant,
+}
+
+impl Worker {
+    fn new(server: Arc<Server>) -> io::Result<(Worker, WorkerHandle)> {
+        let poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), WAKER)?;
+        let (sender, receiver) = mpsc::channel();
+        let worker = Worker {
+            poll,
+            incoming: receiver,
+            server,
+            connections: HashMap::new(),
+            busy: Vec::new(),
+            next_token: 0,
+            last_idle_check: Instant::now(),
+        };
+        Ok((worker, WorkerHandle { sender, waker }))
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        while !STOPPING.load(Ordering::SeqCst) {
+            self.turn(&mut events)?;
+        }
+        self.stop();
+        Ok(())
+    }
+
+    /**
+     * Wait for events, at most `IDLE_CHECK_INTERVAL` or not at all while a
+     * connection is busy, and serve them
+     */
+    fn turn(&mut self, events: &mut Events) -> io::Result<()> {
+        let timeout = if self.busy.is_empty() {
+            IDLE_CHECK_INTERVAL
+        } else {
+            Duration::ZERO
+        };
+        if let Err(e) = self.poll.poll(events, Some(timeout)) {
+            if e.kind() == ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(e);
+        }
+
+        // Connections that were busy go after the ones that had events, so a
+        // client pipelining without pause can't keep the others waiting
+        let busy = mem::take(&mut self.busy);
+        for event in events.iter() {
+            match event.token() {
+                WAKER => self.register_incoming(),
+                token => self.serve(token),
+            }
+        }
+        for token in busy {
+            self.serve(token);
+        }
+
+        if self.last_idle_check.elapsed() >= IDLE_CHECK_INTERVAL {
+            self.close_idle();
+            self.last_idle_check = Instant::now();
+        }
+        Ok(())
+    }
+
+    /**
+     * Send the replies that are still pending, giving up after
+     * `STOP_FLUSH_TIMEOUT`, and close every connection
+     */
+    fn stop(&mut self) {
+        let deadline = Instant::now() + STOP_FLUSH_TIMEOUT;
+        let tokens: Vec<Token> = self.connections.keys().copied().collect();
+        for token in tokens {
+            if let Some(connection) = self.connections.get_mut(&token) {
+                while Instant::now() < deadline && matches!(connection.flush(), Ok(false)) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            self.close(token);
+        }
+    }
+
+    /**
+     * Take over the connections the acceptor handed to this worker
+     */
+    fn register_incoming(&mut self) {
+        while let Ok(mut stream) = self.incoming.try_recv() {
+            self.next_token += 1;
+            let token = Token(self.next_token);
+            if let Err(e) = self
+                .poll
+                .registry()
+                .register(&mut stream, token, Interest::READABLE)
+            {
+                log(
+                    LogLevel::Warning,
+                    &format!("Can't register connection: {}", e),
+                );
+                self.server.clients.fetch_sub(1, Ordering::Relaxed);
+                continue;
+            }
+            let limits = self.server.config.read().unwrap().request_limits();
+            self.connections
+                .insert(token, Connection::new(stream, limits));
+        }
+    }
+
+    fn serve(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        // Pick up limits changed with CONFIG SET
+        let limits = self.server.config.read().unwrap().request_limits();
+        connection.reader.set_limits(limits);
+
+        let handled = connection.handle(&self.server);
+        let open = handled != Handled::Closed
+            && connection
+                .update_interest(self.poll.registry(), token)
+                .is_ok();
+        if !open {
+            self.close(token);
+        } else if handled == Handled::Busy && !self.busy.contains(&token) {
+            self.busy.push(token);
+        }
+    }
+
+    /**
+     * Close connections that haven't sent anything for `timeout` seconds
+     */
+    fn close_idle(&mut self) {
+        let timeout = self.server.config.read().unwrap().timeout;
+        if timeout == 0 {
+            return;
+        }
+
+        let timeout = Duration::from_secs(timeout);
+        let idle: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.last_interaction.elapsed() >= timeout)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            log(LogLevel::Verbose, "Closing idle client");
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            self.poll
+                .registry()
+                .deregister(connection.reader.get_mut())
+                .ok();
+            self.server.clients.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/**
+ * A client socket: plain TCP, TLS or a Unix domain socket
+ */
+enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream>),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /**
+     * The socket to register with the event loop
+     */
+    fn source(&mut self) -> &mut dyn Source {
+        match self {
+            Stream::Tcp(stream) => stream,
+            Stream::Tls(stream) => stream.socket_mut(),
+            Stream::Unix(stream) => stream,
+        }
+    }
+
+    /**
+     * Whether data written earlier still has to be sent
+     */
+    fn wants_write(&self) -> bool {
+        match self {
+            Stream::Tls(stream) => stream.wants_write(),
+            Stream::Tcp(_) | Stream::Unix(_) => false,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interest: Interest,
+    ) -> io::Result<()> {
+        self.source().register(registry, token, interest)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interest: Interest,
+    ) -> io::Result<()> {
+        self.source().reregister(registry, token, interest)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.source().deregister(registry)
+    }
+}
+
+/**
+ * Where `Connection::handle` left a connection
+ */
+#[derive(Debug, PartialEq)]
+enum Handled {
+    /// Waiting for the socket to become readable or writable
+    Waiting,
+    /// Stopped at `MAX_REQUESTS_PER_EVENT`, there may be more input left
+    Busy,
+    /// The connection should be closed
+    Closed,
+}
+
+/**
+ * A client connection and the replies that still have to be sent to it
+ */
+struct Connection {
+    reader: RequestReader<Stream>,
+    out: Vec<u8>,
+    session: Session,
+    interest: Interest,
+    /// When the client last sent something, for the idle timeout
+    last_interaction: Instant,
+    /// A TLS client certificate was already looked at for logging in
+    certificate_checked: bool,
+}
+
+impl Connection {
+    fn new(stream: Stream, limits: Limits) -> Connection {
+        Connection {
+            reader: RequestReader::new(stream, limits),
+            out: Vec::new(),
+            session: Session {
+                user: None,
+                protocol: Protocol::Resp2,
+                closing: false,
+            },
+            interest: Interest::READABLE,
+            last_interaction: Instant::now(),
+            certificate_checked: false,
+        }
+    }
+
+    /**
+     * Log a TLS client in as the user named by the common name of its
+     * certificate, if there is such a user. Only done once, as soon as the
+     * handshake is complete.
+     */
+    fn log_in_with_certificate(&mut self, server: &Server) {
+        let Stream::Tls(stream) = self.reader.get_mut() else {
+            self.certificate_checked = true;
+            return;
+        };
+        if self.certificate_checked || stream.is_handshaking() {
+            return;
+        }
+        self.certificate_checked = true;
+
+        let Some(name) = stream.peer_common_name() else {
+            return;
+        };
+        if server.acl.read().unwrap().is_enabled(&name) {
+            log(
+                LogLevel::Verbose,
+                &format!("Client certificate logged in as user {}", name),
+            );
+            self.session.user = Some(name);
+        } else {
+            log(
+                LogLevel::Verbose,
+                &format!("No enabled user for client certificate {}", name),
+            );
+        }
+    }
+
+    /**
+     * Make as much progress as possible without blocking: run the buffered
+     * requests, send their replies and read more input. Stops after
+     * `MAX_REQUESTS_PER_EVENT` requests so other clients get their turn.
+     */
+    fn handle(&mut self, server: &Server) -> Handled {
+        let mut budget = MAX_REQUESTS_PER_EVENT;
+        loop {
+            budget -= self.run_requests(server, budget);
+
+            match self.flush() {
+                Ok(true) => {}
+                // The socket is full, carry on once the client caught up
+                Ok(false) => return Handled::Waiting,
+                Err(_) => {
+                    log(
+                        LogLevel::Verbose,
+                        "Error writing to client, closing connection",
+                    );
+                    return Handled::Closed;
+                }
+            }
+
+            if self.session.closing {
+                return Handled::Closed;
+            }
+            if budget == 0 {
+                return Handled::Busy;
+            }
+
+            match self.reader.fill() {
+                Ok(0) => {
+                    log(LogLevel::Verbose, "Zero bytes, connection closed");
+                    return Handled::Closed;
+                }
+                Ok(_) => self.last_interaction = Instant::now(),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Handled::Waiting,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    log(
+                        LogLevel::Verbose,
+                        "Error reading client, closing connection",
+                    );
+                    return Handled::Closed;
+                }
+            }
+        }
+    }
+
+    /**
+     * Run up to `limit` complete requests that are already buffered, so
+     * pipelined commands are answered with a single write. Returns how many
+     * were run.
+     */
+    fn run_requests(&mut self, server: &Server, limit: usize) -> usize {
+        if !self.certificate_checked {
+            self.log_in_with_certificate(server);
+        }
+        let mut ran = 0;
+        while !self.session.closing && ran < limit {
+            match self.reader.next_request() {
+                Ok(Some(parts)) => {
+                    if let Some(reply) = execute(&parts, &mut self.session, server) {
+                        reply.encode(self.session.protocol, &mut self.out);
+                    }
+                    ran += 1;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    Reply::Error(format!("ERR {}", e)).encode(self.session.protocol, &mut self.out);
+                    log(LogLevel::Verbose, "Protocol error, closing connection");
+                    self.session.closing = true;
+                }
+            }
+        }
+        ran
+    }
+
+    /**
+     * Write as much of the pending output as the socket takes. Returns
+     * `Ok(false)` if some of it is still left.
+     */
+    fn flush(&mut self) -> io::Result<bool> {
+        let stream = self.reader.get_mut();
+        while !self.out.is_empty() {
+            match stream.write(&self.out) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // TLS keeps its own buffer of encrypted data
+        match stream.flush() {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+     * Wait for the socket to become writable while replies are pending, and
+     * only read new requests once they are out. A client that doesn't read its
+     * replies therefore can't make the server buffer an unbounded amount.
+     */
+    fn update_interest(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = if self.out.is_empty() && !self.reader.get_mut().wants_write() {
+            Interest::READABLE
+        } else {
+            Interest::WRITABLE
+        };
+        if interest != self.interest {
+            registry.reregister(self.reader.get_mut(), token, interest)?;
+            self.interest = interest;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net;
+
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    use super::*;
+    use crate::config::{Config, TlsAuthClients};
+    use crate::testing::{self, Identity, TempFile};
+    use crate::tls;
+
+    /**
+     * An acceptor handing connections to a single worker, which the test
+     * drives itself
+     */
+    fn acceptor(config: Config) -> (Acceptor, Worker) {
+        let server = testing::server(config);
+        let (worker, handle) = Worker::new(Arc::clone(&server)).unwrap();
+        let acceptor = Acceptor {
+            poll: Poll::new().unwrap(),
+            server,
+            workers: vec![handle],
+            next_worker: 0,
+        };
+        (acceptor, worker)
+    }
+
+    /**
+     * Hand one end of a socket pair over as a new connection and return the
+     * other end, for the test to play the client with
+     */
+    fn connect(acceptor: &mut Acceptor) -> net::UnixStream {
+        let (client, socket) = net::UnixStream::pair().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        acceptor
+            .hand_over(Stream::Unix(UnixStream::from_std(socket)), None, false)
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+    }
+
+    /**
+     * Whether the server closed the client's connection
+     */
+    fn closed(client: &mut net::UnixStream) -> bool {
+        client.set_nonblocking(true).unwrap();
+        let read = client.read(&mut [0; 64]);
+        client.set_nonblocking(false).unwrap();
+        matches!(read, Ok(0))
+    }
+
+    /**
+     * Read exactly `len` bytes of replies
+     */
+    fn replies(client: &mut net::UnixStream, len: usize) -> Vec<u8> {
+        let mut replies = vec![0; len];
+        client.read_exact(&mut replies).unwrap();
+        replies
+    }
+
+    /**
+     * Whether nothing more has arrived for the client yet
+     */
+    fn nothing_pending(client: &net::UnixStream) -> bool {
+        client.set_nonblocking(true).unwrap();
+        let pending = (&*client).read(&mut [0; 1]);
+        client.set_nonblocking(false).unwrap();
+        matches!(pending, Err(e) if e.kind() == ErrorKind::WouldBlock)
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order_with_one_flush() {
+        let server = testing::server(Config::default());
+        let (mut client, socket) = net::UnixStream::pair().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let limits = server.config.read().unwrap().request_limits();
+        let mut connection = Connection::new(Stream::Unix(UnixStream::from_std(socket)), limits);
+
+        client
+            .write_all(b"SET k 1\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\nAPPEND k 2\r\nGET k\r\nPING\r\n")
+            .unwrap();
+        connection.reader.fill().unwrap();
+        assert_eq!(connection.run_requests(&server, MAX_REQUESTS_PER_EVENT), 5);
+        let expected = b"+OK\r\n$1\r\n1\r\n:2\r\n$2\r\n12\r\n+PONG\r\n";
+        assert_eq!(connection.out, expected);
+
+        assert!(connection.flush().unwrap());
+        assert!(connection.out.is_empty());
+        assert_eq!(replies(&mut client, expected.len()), expected);
+    }
+
+    #[test]
+    fn a_pipelining_client_does_not_starve_the_others() {
+        let (mut acceptor, mut worker) = acceptor(Config::default());
+        let mut events = Events::with_capacity(16);
+        let mut busy = connect(&mut acceptor);
+        let mut quiet = connect(&mut acceptor);
+        busy.write_all(&b"PING\r\n".repeat(3 * MAX_REQUESTS_PER_EVENT))
+            .unwrap();
+        quiet.write_all(b"PING\r\n").unwrap();
+
+        // The first turn takes the connections over, the second serves them
+        worker.turn(&mut events).unwrap();
+        worker.turn(&mut events).unwrap();
+        assert_eq!(replies(&mut quiet, 7), b"+PONG\r\n");
+        assert_eq!(
+            replies(&mut busy, 7 * MAX_REQUESTS_PER_EVENT),
+            b"+PONG\r\n".repeat(MAX_REQUESTS_PER_EVENT)
+        );
+        assert!(nothing_pending(&busy));
+        assert_eq!(worker.busy.len(), 1);
+
+        // The rest is served without waiting for another event
+        while !worker.busy.is_empty() {
+            worker.turn(&mut events).unwrap();
+        }
+        assert_eq!(
+            replies(&mut busy, 14 * MAX_REQUESTS_PER_EVENT),
+            b"+PONG\r\n".repeat(2 * MAX_REQUESTS_PER_EVENT)
+        );
+        assert!(nothing_pending(&busy));
+    }
+
+    #[test]
+    fn protected_mode_only_turns_away_remote_clients_without_a_password() {
+        let loopback: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:50000".parse().unwrap();
+        let remote: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+
+        assert!(!protected_mode_denies(true, Some(loopback), false));
+        assert!(!protected_mode_denies(true, Some(mapped), false));
+        assert!(!protected_mode_denies(true, None, false));
+        assert!(protected_mode_denies(true, Some(remote), false));
+        assert!(!protected_mode_denies(true, Some(remote), true));
+        assert!(!protected_mode_denies(false, Some(remote), false));
+    }
+
+    #[test]
+    fn clients_beyond_maxclients_are_turned_away() {
+        let (mut acceptor, mut worker) = acceptor(Config {
+            maxclients: 1,
+            ..Config::default()
+        });
+        let mut events = Events::with_capacity(16);
+
+        let first = connect(&mut acceptor);
+        let mut second = connect(&mut acceptor);
+        let mut refused = String::new();
+        second.read_to_string(&mut refused).unwrap();
+        assert_eq!(refused, "-ERR max number of clients reached\r\n");
+
+        // Once the first client is gone there is room again
+        drop(first);
+        while acceptor.server.clients.load(Ordering::Relaxed) > 0 {
+            worker.turn(&mut events).unwrap();
+        }
+        let mut third = connect(&mut acceptor);
+        third.write_all(b"PING\r\n").unwrap();
+        worker.turn(&mut events).unwrap();
+        worker.turn(&mut events).unwrap();
+        assert_eq!(replies(&mut third, 7), b"+PONG\r\n");
+    }
+
+    #[test]
+    fn idle_clients_are_closed_after_the_timeout() {
+        let (mut acceptor, mut worker) = acceptor(Config {
+            timeout: 2,
+            ..Config::default()
+        });
+        let mut events = Events::with_capacity(16);
+
+        let connected = Instant::now();
+        let mut idle = connect(&mut acceptor);
+        while !closed(&mut idle) && connected.elapsed() < Duration::from_secs(5) {
+            worker.turn(&mut events).unwrap();
+        }
+        let elapsed = connected.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(2) && elapsed < Duration::from_secs(4),
+            "closed after {:?}",
+            elapsed
+        );
+        assert_eq!(acceptor.server.clients.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn keepalive_probes_follow_the_setting() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = TcpStream::from_std(client);
+        let socket = SockRef::from(&stream);
+
+        set_keepalive(&stream, 300).unwrap();
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            socket.tcp_keepalive_interval().unwrap(),
+            Duration::from_secs(100)
+        );
+
+        set_keepalive(&stream, 2).unwrap();
+        assert_eq!(
+            socket.tcp_keepalive_interval().unwrap(),
+            Duration::from_secs(1)
+        );
+
+        set_keepalive(&stream, 0).unwrap();
+        assert!(!socket.keepalive().unwrap());
+    }
+
+    #[test]
+    fn unix_socket_gets_its_permissions_and_serves_clients() {
+        let path = TempFile::reserve("keyvy.sock");
+        let config = Config {
+            bind: Vec::new(),
+            unixsocket: path.path().to_str().unwrap().to_string(),
+            unixsocketperm: 0o700,
+            ..Config::default()
+        };
+        let mut listeners = crate::bind_listeners(&config).unwrap();
+        let mode = fs::metadata(path.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let Some(Listener::Unix(listener)) = listeners.pop() else {
+            panic!("expected only the Unix socket listener");
+        };
+        listener.set_nonblocking(true).unwrap();
+        let socket = Socket::Unix(UnixListener::from_std(listener));
+        let (mut acceptor, mut worker) = acceptor(config);
+        let mut events = Events::with_capacity(16);
+
+        let mut client = net::UnixStream::connect(path.path()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        acceptor.accept(&socket).unwrap();
+        client.write_all(b"PING\r\n").unwrap();
+        worker.turn(&mut events).unwrap();
+        worker.turn(&mut events).unwrap();
+        assert_eq!(replies(&mut client, 7), b"+PONG\r\n");
+    }
+
+    #[test]
+    fn client_certificate_logs_in_as_its_user_while_enabled() {
+        let ca = Identity::ca();
+        let identity = Identity::issue("server", &ca);
+        let alice = Identity::issue("alice", &ca);
+        let files = [
+            identity.cert_file("server"),
+            identity.key_file("server"),
+            ca.cert_file("ca"),
+        ];
+        let [cert, key, ca_cert] = files
+            .each_ref()
+            .map(|file| file.path().to_str().unwrap().to_string());
+        let config = Config {
+            tls_cert_file: cert,
+            tls_key_file: key,
+            tls_ca_cert_file: ca_cert,
+            tls_auth_clients: TlsAuthClients::Yes,
+            ..Config::default()
+        };
+        let tls = tls::load(&config).unwrap();
+        let server = testing::server(config);
+        // Without a password everyone would end up as `default`
+        server
+            .acl
+            .write()
+            .unwrap()
+            .set_user(acl::DEFAULT_USER, &[">s3cret"])
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.cert.der().clone()).unwrap();
+        let client_config = Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_client_auth_cert(
+                    vec![alice.cert.der().clone()],
+                    PrivatePkcs8KeyDer::from(alice.key.serialize_der()).into(),
+                )
+                .unwrap(),
+        );
+
+        for (rules, user) in [("on", Some("alice")), ("off", None)] {
+            server
+                .acl
+                .write()
+                .unwrap()
+                .set_user("alice", &[rules])
+                .unwrap();
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let client_config = Arc::clone(&client_config);
+            let client = thread::spawn(move || {
+                let name = "localhost".try_into().unwrap();
+                let connection = ClientConnection::new(client_config, name).unwrap();
+                let socket = std::net::TcpStream::connect(address).unwrap();
+                let mut stream = StreamOwned::new(connection, socket);
+                stream.write_all(b"PING\r\n").unwrap();
+                let mut reply = [0; 7];
+                stream.read_exact(&mut reply).unwrap();
+                reply
+            });
+
+            // The socket is left blocking, so reading waits out the handshake
+            let (socket, _) = listener.accept().unwrap();
+            let stream = TlsStream::new(TcpStream::from_std(socket), Arc::clone(&tls)).unwrap();
+            let limits = server.config.read().unwrap().request_limits();
+            let mut connection = Connection::new(Stream::Tls(Box::new(stream)), limits);
+            connection.reader.fill().unwrap();
+            connection.run_requests(&server, MAX_REQUESTS_PER_EVENT);
+            assert_eq!(connection.session.user.as_deref(), user, "user {}", rules);
+
+            assert!(connection.flush().unwrap());
+            assert_eq!(&client.join().unwrap(), b"+PONG\r\n");
+        }
+    }
+}
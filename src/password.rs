@@ -0,0 +1,145 @@
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/**
+ * Number of random bytes in a generated salt
+ */
+const SALT_LEN: usize = 16;
+
+/**
+ * A salted SHA-256 password hash, written as `#<salt>:<hash>` with both parts
+ * in hex. The hash covers the salt's hex text followed by the password, so it
+ * can also be produced with `printf '%s%s' <salt> <password> | sha256sum`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PasswordHash {
+    salt: String,
+    hash: [u8; 32],
+}
+
+impl PasswordHash {
+    /**
+     * Hash `password` with a new random salt
+     */
+    pub fn new(password: &[u8]) -> PasswordHash {
+        let mut salt = [0; SALT_LEN];
+        getrandom::getrandom(&mut salt).expect("no random numbers available for the salt");
+        let salt = to_hex(&salt);
+        let hash = digest(&salt, password);
+        PasswordHash { salt, hash }
+    }
+
+    /**
+     * Parse the `#<salt>:<hash>` form produced by `Display`
+     */
+    pub fn parse(text: &str) -> Option<PasswordHash> {
+        let (salt, hash) = text.strip_prefix('#')?.split_once(':')?;
+        if salt.is_empty() || !salt.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(PasswordHash {
+            salt: salt.to_ascii_lowercase(),
+            hash: from_hex(hash)?.try_into().ok()?,
+        })
+    }
+
+    /**
+     * Whether `password` is the one this hash was made from. Takes the same
+     * time wherever the first differing byte is.
+     */
+    pub fn verify(&self, password: &[u8]) -> bool {
+        digest(&self.salt, password).ct_eq(&self.hash).into()
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}:{}", self.salt, to_hex(&self.hash))
+    }
+}
+
+fn digest(salt: &str, password: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(password);
+    hasher.finalize().into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_and_parse_round_trip() {
+        let hash = PasswordHash::new(b"s3cret");
+        let text = hash.to_string();
+        assert!(text.starts_with('#'));
+        assert_eq!(text.len(), 1 + 2 * SALT_LEN + 1 + 64);
+        assert_eq!(PasswordHash::parse(&text), Some(hash));
+    }
+
+    #[test]
+    fn salts_differ_between_hashes() {
+        assert_ne!(PasswordHash::new(b"same"), PasswordHash::new(b"same"));
+    }
+
+    #[test]
+    fn verify_accepts_only_the_password() {
+        let hash = PasswordHash::new(b"s3cret");
+        assert!(hash.verify(b"s3cret"));
+        assert!(!hash.verify(b"s3cre"));
+        assert!(!hash.verify(b"s3cret "));
+        assert!(!hash.verify(b""));
+        assert!(PasswordHash::new(b"").verify(b""));
+    }
+
+    #[test]
+    fn matches_the_sha256sum_recipe() {
+        // printf '%s%s' 5f1c0e9a s3cret | sha256sum
+        let text = "#5f1c0e9a:8fd52a7d85438066f6735798f313bfffb0026a2256da23ecb3813d29728343a5";
+        let hash = PasswordHash::parse(text).unwrap();
+        assert!(hash.verify(b"s3cret"));
+        assert!(!hash.verify(b"secret"));
+        assert_eq!(hash.to_string(), text);
+
+        // Hex digits are case-insensitive
+        let upper = PasswordHash::parse(&text.to_uppercase()).unwrap();
+        assert!(upper.verify(b"s3cret"));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_hashes() {
+        let hash = "8fd52a7d85438066f6735798f313bfffb0026a2256da23ecb3813d29728343a5";
+        for text in [
+            String::new(),
+            "s3cret".to_string(),
+            format!("5f1c0e9a:{}", hash),
+            format!("#5f1c0e9a{}", hash),
+            format!("#:{}", hash),
+            format!("#5f1c0e9g:{}", hash),
+            format!("#5f1c0e9a:{}", &hash[1..]),
+            format!("#5f1c0e9a:{}", &hash[2..]),
+            format!("#5f1c0e9a:{}00", hash),
+            format!("#5f1c0e9a:{}zz", &hash[2..]),
+            "#5f1c0e9a:".to_string(),
+        ] {
+            assert_eq!(PasswordHash::parse(&text), None, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,695 @@
+use std::fmt;
+use std::io::{self, Read};
+
+/**
+ * A single client request: the command name followed by its arguments,
+ * exactly as they were sent on the wire.
+ */
+pub type Request = Vec<Vec<u8>>;
+
+#[derive(Debug)]
+pub struct ProtocolError(String);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Protocol error: {}", self.0)
+    }
+}
+
+/**
+ * Largest requests a client may send. Exceeding any of them is a protocol
+ * error, detected as soon as the offending header or line is seen so an
+ * oversized request is never buffered in full.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Longest inline request or `*`/`$` header line, in bytes
+    pub max_line: usize,
+    /// Longest single argument, in bytes
+    pub max_bulk: usize,
+    /// Most arguments in one request, including the command name
+    pub max_args: usize,
+}
+
+impl Limits {
+    /**
+     * No limits, for input that is trusted such as the append-only file
+     */
+    pub const NONE: Limits = Limits {
+        max_line: usize::MAX,
+        max_bulk: usize::MAX,
+        max_args: usize::MAX,
+    };
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+/**
+ * Read a `\r\n` terminated integer line (e.g. the `3` in `*3\r\n`) starting at `pos`.
+ * Returns the number and the position right after the line terminator.
+ */
+fn parse_length(
+    buf: &[u8],
+    pos: usize,
+    limits: &Limits,
+) -> Result<Option<(i64, usize)>, ProtocolError> {
+    let end = match find_crlf(&buf[pos..]) {
+        Some(end) if end <= limits.max_line => pos + end,
+        None if buf.len() - pos <= limits.max_line => return Ok(None),
+        _ => return Err(ProtocolError("too big count string".to_string())),
+    };
+
+    let value = std::str::from_utf8(&buf[pos..end])
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| ProtocolError("invalid length".to_string()))?;
+
+    Ok(Some((value, end + 2)))
+}
+
+/**
+ * A multibulk request whose arguments have only partly been parsed
+ */
+#[derive(Debug)]
+struct Multibulk {
+    /// Number of arguments announced in the `*<n>` header
+    count: usize,
+    args: Request,
+    /// Bytes of the request parsed so far, i.e. where the next argument starts
+    pos: usize,
+}
+
+/**
+ * Parse the `*<n>\r\n` header of a multibulk request.
+ */
+fn parse_multibulk_header(buf: &[u8], limits: &Limits) -> Result<Option<Multibulk>, ProtocolError> {
+    let (count, pos) = match parse_length(buf, 1, limits)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    if usize::try_from(count).is_ok_and(|count| count > limits.max_args) {
+        return Err(ProtocolError("invalid multibulk length".to_string()));
+    }
+
+    Ok(Some(Multibulk {
+        // A negative count is a null request, handled like an empty one
+        count: count.max(0) as usize,
+        args: Vec::new(),
+        pos,
+    }))
+}
+
+/**
+ * Parse as many of the remaining `$<len>\r\n<bytes>\r\n` arguments of
+ * `request` as `buf` holds, `buf` starting where the request does. Returns
+ * whether the request is complete. Arguments parsed earlier are not looked at
+ * again, so a request arriving in many pieces is still parsed in linear time.
+ */
+fn parse_multibulk_args(
+    buf: &[u8],
+    request: &mut Multibulk,
+    limits: &Limits,
+) -> Result<bool, ProtocolError> {
+    while request.args.len() < request.count {
+        let pos = request.pos;
+        if pos >= buf.len() {
+            return Ok(false);
+        }
+        if buf[pos] != b'$' {
+            return Err(ProtocolError(format!(
+                "expected '$', got '{}'",
+                buf[pos] as char
+            )));
+        }
+
+        let (len, start) = match parse_length(buf, pos + 1, limits)? {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+        if len < 0 || len as usize > limits.max_bulk {
+            return Err(ProtocolError("invalid bulk length".to_string()));
+        }
+
+        let end = start + len as usize;
+        if buf.len() < end + 2 {
+            return Ok(false);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(ProtocolError(
+                "bulk string is not terminated by CRLF".to_string(),
+            ));
+        }
+
+        request.args.push(buf[start..end].to_vec());
+        request.pos = end + 2;
+    }
+    Ok(true)
+}
+
+/**
+ * Parse a multibulk request (`*<n>\r\n$<len>\r\n<bytes>\r\n...`).
+ */
+fn parse_multibulk(buf: &[u8], limits: &Limits) -> Result<Option<(Request, usize)>, ProtocolError> {
+    let Some(mut request) = parse_multibulk_header(buf, limits)? else {
+        return Ok(None);
+    };
+    if !parse_multibulk_args(buf, &mut request, limits)? {
+        return Ok(None);
+    }
+    Ok(Some((request.args, request.pos)))
+}
+
+/**
+ * Parse an inline request: a single line of whitespace separated words,
+ * as typed into telnet or netcat.
+ */
+fn parse_inline(buf: &[u8], limits: &Limits) -> Result<Option<(Request, usize)>, ProtocolError> {
+    let end = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) if end <= limits.max_line => end,
+        None if buf.len() <= limits.max_line => return Ok(None),
+        _ => return Err(ProtocolError("too big inline request".to_string())),
+    };
+
+    let args = buf[..end]
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_vec())
+        .collect();
+
+    Ok(Some((args, end + 1)))
+}
+
+/**
+ * Try to parse one request from the start of `buf`.
+ *
+ * Returns `Ok(None)` when the buffer does not hold a complete request yet,
+ * otherwise the request and the number of bytes it occupied. An empty request
+ * (blank inline line, `*0`) is returned as an empty vector.
+ */
+pub fn parse_request(
+    buf: &[u8],
+    limits: &Limits,
+) -> Result<Option<(Request, usize)>, ProtocolError> {
+    match buf.first() {
+        None => Ok(None),
+        Some(b'*') => parse_multibulk(buf, limits),
+        Some(_) => parse_inline(buf, limits),
+    }
+}
+
+/**
+ * Reads requests from a client connection, buffering partial input until a
+ * full request is available.
+ */
+pub struct RequestReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    pos: usize,
+    limits: Limits,
+    /// Multibulk request starting at `pos` that is still waiting for input
+    partial: Option<Multibulk>,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(inner: R, limits: Limits) -> RequestReader<R> {
+        RequestReader {
+            inner,
+            buffer: Vec::with_capacity(16 * 1024),
+            pos: 0,
+            limits,
+            partial: None,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /**
+     * The underlying connection, e.g. to write replies to it
+     */
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /**
+     * Take the next complete, non-empty request out of the buffer without
+     * touching the socket. Returns `Ok(None)` when more input is needed.
+     */
+    pub fn next_request(&mut self) -> Result<Option<Request>, ProtocolError> {
+        loop {
+            let buf = &self.buffer[self.pos..];
+            let request = match self.partial.as_mut() {
+                Some(request) => request,
+                None if buf.first() == Some(&b'*') => {
+                    match parse_multibulk_header(buf, &self.limits)? {
+                        Some(request) => self.partial.insert(request),
+                        None => return Ok(None),
+                    }
+                }
+                None => match parse_request(buf, &self.limits)? {
+                    Some((request, consumed)) => {
+                        self.pos += consumed;
+                        if !request.is_empty() {
+                            return Ok(Some(request));
+                        }
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+
+            // Keep the arguments parsed so far for the next call rather than
+            // starting over once more input arrived
+            if !parse_multibulk_args(buf, request, &self.limits)? {
+                return Ok(None);
+            }
+            let request = self.partial.take().unwrap();
+            self.pos += request.pos;
+            if !request.args.is_empty() {
+                return Ok(Some(request.args));
+            }
+        }
+    }
+
+    /**
+     * Read more input from the client. Returns the number of bytes read, `0`
+     * once the client closed the connection. On a non-blocking connection
+     * this fails with `WouldBlock` when no input is available.
+     */
+    pub fn fill(&mut self) -> io::Result<usize> {
+        // Drop what was already consumed before reading more
+        self.buffer.drain(..self.pos);
+        self.pos = 0;
+
+        let mut chunk = [0_u8; 16 * 1024];
+        let n = self.inner.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+/**
+ * Wire protocol version spoken with a client, negotiated through HELLO.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    pub fn version(self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+/**
+ * A typed reply. Types that only exist in RESP3 are downgraded to their
+ * closest RESP2 equivalent when encoding for a RESP2 client.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    /// A flat array of keys and values in RESP2
+    Map(Vec<(Reply, Reply)>),
+    /// An array in RESP2. No command replies with a set yet.
+    #[allow(dead_code)]
+    Set(Vec<Reply>),
+    /// A bulk string in RESP2. No command replies with a double yet.
+    #[allow(dead_code)]
+    Double(f64),
+    /// `1` or `0` in RESP2. No command replies with a boolean yet.
+    #[allow(dead_code)]
+    Boolean(bool),
+    /// An array in RESP2. Nothing is pushed to clients yet.
+    #[allow(dead_code)]
+    Push(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn ok() -> Reply {
+        Reply::Simple("OK".to_string())
+    }
+
+    pub fn error(message: &str) -> Reply {
+        Reply::Error(message.to_string())
+    }
+
+    pub fn bulk(value: &str) -> Reply {
+        Reply::Bulk(value.as_bytes().to_vec())
+    }
+
+    pub fn encode(&self, protocol: Protocol, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => encode_line(out, b'+', s),
+            Reply::Error(e) => encode_line(out, b'-', e),
+            Reply::Integer(i) => encode_line(out, b':', &i.to_string()),
+            Reply::Bulk(bytes) => {
+                encode_line(out, b'$', &bytes.len().to_string());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Null => match protocol {
+                Protocol::Resp2 => out.extend_from_slice(b"$-1\r\n"),
+                Protocol::Resp3 => out.extend_from_slice(b"_\r\n"),
+            },
+            Reply::Array(items) => encode_aggregate(out, b'*', items, protocol),
+            Reply::Set(items) => match protocol {
+                Protocol::Resp2 => encode_aggregate(out, b'*', items, protocol),
+                Protocol::Resp3 => encode_aggregate(out, b'~', items, protocol),
+            },
+            Reply::Push(items) => match protocol {
+                Protocol::Resp2 => encode_aggregate(out, b'*', items, protocol),
+                Protocol::Resp3 => encode_aggregate(out, b'>', items, protocol),
+            },
+            Reply::Map(pairs) => {
+                match protocol {
+                    Protocol::Resp2 => encode_line(out, b'*', &(pairs.len() * 2).to_string()),
+                    Protocol::Resp3 => encode_line(out, b'%', &pairs.len().to_string()),
+                }
+                for (key, value) in pairs {
+                    key.encode(protocol, out);
+                    value.encode(protocol, out);
+                }
+            }
+            Reply::Double(d) => {
+                let text = format_double(*d);
+                match protocol {
+                    Protocol::Resp2 => Reply::bulk(&text).encode(protocol, out),
+                    Protocol::Resp3 => encode_line(out, b',', &text),
+                }
+            }
+            Reply::Boolean(b) => match protocol {
+                Protocol::Resp2 => Reply::Integer(*b as i64).encode(protocol, out),
+                Protocol::Resp3 => encode_line(out, b'#', if *b { "t" } else { "f" }),
+            },
+        }
+    }
+}
+
+/**
+ * Write a single-line reply. Line breaks are replaced with spaces, so text
+ * that echoes client input, such as an error naming an unknown command, can't
+ * end the line early and inject a reply of its own.
+ */
+fn encode_line(out: &mut Vec<u8>, prefix: u8, line: &str) {
+    out.push(prefix);
+    out.extend(line.bytes().map(|byte| {
+        if byte == b'\r' || byte == b'\n' {
+            b' '
+        } else {
+            byte
+        }
+    }));
+    out.extend_from_slice(b"\r\n");
+}
+
+fn encode_aggregate(out: &mut Vec<u8>, prefix: u8, items: &[Reply], protocol: Protocol) {
+    encode_line(out, prefix, &items.len().to_string());
+    for item in items {
+        item.encode(protocol, out);
+    }
+}
+
+fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::args;
+
+    const SMALL: Limits = Limits {
+        max_line: 8,
+        max_bulk: 4,
+        max_args: 3,
+    };
+
+    fn parse(input: &[u8]) -> Option<(Request, usize)> {
+        parse_request(input, &Limits::NONE).unwrap()
+    }
+
+    #[test]
+    fn parses_multibulk() {
+        let input = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\nrest";
+        assert_eq!(parse(input), Some((args(&["GET", "k"]), 20)));
+    }
+
+    #[test]
+    fn keeps_binary_arguments() {
+        let input = b"*1\r\n$4\r\n\x00\r\n\xff\r\n";
+        assert_eq!(
+            parse(input),
+            Some((vec![b"\x00\r\n\xff".to_vec()], input.len()))
+        );
+    }
+
+    #[test]
+    fn waits_for_partial_multibulk() {
+        let input = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        for end in 0..input.len() {
+            assert_eq!(parse(&input[..end]), None, "prefix of {} bytes", end);
+        }
+    }
+
+    #[test]
+    fn empty_and_null_multibulk_are_empty_requests() {
+        assert_eq!(parse(b"*0\r\n"), Some((vec![], 4)));
+        assert_eq!(parse(b"*-1\r\n"), Some((vec![], 5)));
+    }
+
+    #[test]
+    fn rejects_malformed_multibulk() {
+        for input in [
+            &b"*x\r\n"[..],
+            b"*1\r\n:1\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*1\r\n$1\r\nab\r\n",
+        ] {
+            assert!(parse_request(input, &Limits::NONE).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parses_inline() {
+        assert_eq!(parse(b"SET  k v\r\n"), Some((args(&["SET", "k", "v"]), 10)));
+        assert_eq!(parse(b"PING\n"), Some((args(&["PING"]), 5)));
+        assert_eq!(parse(b"\r\n"), Some((vec![], 2)));
+        assert_eq!(parse(b"PING"), None);
+    }
+
+    #[test]
+    fn accepts_requests_at_the_limits() {
+        let input = b"*3\r\n$4\r\nabcd\r\n$0\r\n\r\n$1\r\nx\r\n";
+        let parsed = parse_request(input, &SMALL).unwrap();
+        assert_eq!(parsed, Some((args(&["abcd", "", "x"]), input.len())));
+        assert!(parse_request(b"12345678\n", &SMALL).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_requests_over_the_limits() {
+        for input in [
+            &b"*4\r\n"[..],
+            b"*1\r\n$5\r\n",
+            b"*1\r\n$000000004\r\n",
+            b"123456789\n",
+            b"123456789",
+        ] {
+            assert!(parse_request(input, &SMALL).is_err(), "{:?}", input);
+        }
+    }
+
+    fn encoded(reply: &Reply, protocol: Protocol) -> String {
+        let mut out = Vec::new();
+        reply.encode(protocol, &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    /**
+     * Check the encoding of `reply` in both protocol versions
+     */
+    fn assert_encodes(reply: Reply, resp2: &str, resp3: &str) {
+        assert_eq!(
+            encoded(&reply, Protocol::Resp2),
+            resp2,
+            "{:?} in RESP2",
+            reply
+        );
+        assert_eq!(
+            encoded(&reply, Protocol::Resp3),
+            resp3,
+            "{:?} in RESP3",
+            reply
+        );
+    }
+
+    #[test]
+    fn encodes_types_shared_by_both_protocols() {
+        assert_encodes(Reply::ok(), "+OK\r\n", "+OK\r\n");
+        assert_encodes(Reply::error("ERR no"), "-ERR no\r\n", "-ERR no\r\n");
+        assert_encodes(Reply::Integer(-42), ":-42\r\n", ":-42\r\n");
+        assert_encodes(
+            Reply::bulk("a\r\nb"),
+            "$4\r\na\r\nb\r\n",
+            "$4\r\na\r\nb\r\n",
+        );
+        assert_encodes(Reply::bulk(""), "$0\r\n\r\n", "$0\r\n\r\n");
+        assert_encodes(
+            Reply::Array(vec![Reply::Integer(1), Reply::bulk("x")]),
+            "*2\r\n:1\r\n$1\r\nx\r\n",
+            "*2\r\n:1\r\n$1\r\nx\r\n",
+        );
+        assert_encodes(Reply::Array(vec![]), "*0\r\n", "*0\r\n");
+    }
+
+    #[test]
+    fn line_breaks_in_simple_replies_become_spaces() {
+        assert_encodes(
+            Reply::error("ERR unknown command 'X\r\n+OK'"),
+            "-ERR unknown command 'X  +OK'\r\n",
+            "-ERR unknown command 'X  +OK'\r\n",
+        );
+        assert_encodes(
+            Reply::Simple("a\nb\rc".to_string()),
+            "+a b c\r\n",
+            "+a b c\r\n",
+        );
+    }
+
+    #[test]
+    fn encodes_null_per_protocol() {
+        assert_encodes(Reply::Null, "$-1\r\n", "_\r\n");
+        assert_encodes(
+            Reply::Array(vec![Reply::Null]),
+            "*1\r\n$-1\r\n",
+            "*1\r\n_\r\n",
+        );
+    }
+
+    #[test]
+    fn downgrades_resp3_aggregates_to_arrays() {
+        let pairs = vec![
+            (Reply::bulk("a"), Reply::Integer(1)),
+            (Reply::bulk("b"), Reply::Null),
+        ];
+        assert_encodes(
+            Reply::Map(pairs),
+            "*4\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$-1\r\n",
+            "%2\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n_\r\n",
+        );
+        assert_encodes(
+            Reply::Set(vec![Reply::bulk("x")]),
+            "*1\r\n$1\r\nx\r\n",
+            "~1\r\n$1\r\nx\r\n",
+        );
+        assert_encodes(
+            Reply::Push(vec![Reply::bulk("message"), Reply::Integer(1)]),
+            "*2\r\n$7\r\nmessage\r\n:1\r\n",
+            ">2\r\n$7\r\nmessage\r\n:1\r\n",
+        );
+    }
+
+    #[test]
+    fn downgrades_doubles_and_booleans() {
+        assert_encodes(Reply::Double(1.5), "$3\r\n1.5\r\n", ",1.5\r\n");
+        assert_encodes(Reply::Double(-3.0), "$2\r\n-3\r\n", ",-3\r\n");
+        assert_encodes(Reply::Double(f64::INFINITY), "$3\r\ninf\r\n", ",inf\r\n");
+        assert_encodes(
+            Reply::Double(f64::NEG_INFINITY),
+            "$4\r\n-inf\r\n",
+            ",-inf\r\n",
+        );
+        assert_encodes(Reply::Double(f64::NAN), "$3\r\nnan\r\n", ",nan\r\n");
+        assert_encodes(Reply::Boolean(true), ":1\r\n", "#t\r\n");
+        assert_encodes(Reply::Boolean(false), ":0\r\n", "#f\r\n");
+    }
+
+    /**
+     * Hands out its input in fixed chunks, like a socket receiving packets
+     */
+    struct Chunked(Vec<Vec<u8>>);
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn reader_joins_split_requests_and_skips_empty_ones() {
+        let input = b"*0\r\n*1\r\n$4\r\nPING\r\n\r\nGET k\r\n*2\r\n$3\r\nGET\r\n$1\r\nx\r\n";
+        let chunks = input.chunks(3).map(<[u8]>::to_vec).collect();
+        let mut reader = RequestReader::new(Chunked(chunks), Limits::NONE);
+
+        let mut requests = Vec::new();
+        loop {
+            while let Some(request) = reader.next_request().unwrap() {
+                requests.push(request);
+            }
+            if reader.fill().unwrap() == 0 {
+                break;
+            }
+        }
+        assert_eq!(
+            requests,
+            vec![args(&["PING"]), args(&["GET", "k"]), args(&["GET", "x"])]
+        );
+    }
+
+    #[test]
+    fn reader_parses_a_large_request_arriving_in_chunks() {
+        let count = 200_000;
+        let mut input = format!("*{}\r\n", count + 1).into_bytes();
+        input.extend_from_slice(b"$4\r\nMSET\r\n");
+        for i in 0..count {
+            input.extend_from_slice(format!("$1\r\n{}\r\n", i % 10).as_bytes());
+        }
+        input.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+        let chunks = input.chunks(16 * 1024 - 3).map(<[u8]>::to_vec).collect();
+        let mut reader = RequestReader::new(Chunked(chunks), Limits::NONE);
+
+        let mut requests = Vec::new();
+        while reader.fill().unwrap() > 0 {
+            while let Some(request) = reader.next_request().unwrap() {
+                requests.push(request);
+            }
+        }
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].len(), count + 1);
+        assert_eq!(requests[0][0], b"MSET");
+        assert_eq!(requests[0][count], b"9");
+        assert_eq!(requests[1], args(&["PING"]));
+    }
+
+    #[test]
+    fn reader_applies_changed_limits() {
+        let input = b"*1\r\n$5\r\nhello\r\n".to_vec();
+        let mut reader = RequestReader::new(Chunked(vec![input]), Limits::NONE);
+        reader.fill().unwrap();
+        reader.set_limits(SMALL);
+        assert!(reader.next_request().is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use crate::config::LogLevel;
+use crate::logger::log;
+use crate::store::{self, Store, StoreReadGuard};
+use crate::CacheEntry;
+
+/**
+ * Snapshot file layout:
+ *
+ * ```text
+ * "KEYVY" <version: u8>
+ * repeated: <ENTRY: u8> <expires at, unix ms, 0 = never: u64>
+ *           <key length: u32> <key> <value length: u32> <value>
+ * <EOF: u8> <crc32 of everything before: u32>
+ * ```
+ *
+ * All integers are little endian.
+ */
+const MAGIC: &[u8] = b"KEYVY";
+const VERSION: u8 = 1;
+const ENTRY: u8 = 0x01;
+const EOF: u8 = 0xFF;
+
+static BGSAVE_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Numbers the temporary files of concurrent writes, so they never share one
+ */
+static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn corrupted(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/**
+ * Encode the whole keyspace, skipping keys that already expired.
+ */
+pub fn serialize(store: &StoreReadGuard) -> Vec<u8> {
+    let now = Instant::now();
+    let mut data = Vec::with_capacity(store.used_memory());
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+
+    for (key, entry) in store.iter() {
+        let expires_at = match entry.expires_at {
+            Some(expiration) if expiration <= now => continue,
+            Some(expiration) => store::to_unix_millis(expiration),
+            None => 0,
+        };
+
+        data.push(ENTRY);
+        data.extend_from_slice(&expires_at.to_le_bytes());
+        push_bytes(&mut data, key);
+        push_bytes(&mut data, &entry.value);
+    }
+
+    data.push(EOF);
+    let checksum = crc32(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    data
+}
+
+/**
+ * Append `bytes` with its length. `proto-max-bulk-len` keeps every key and
+ * value short enough for the 32-bit length.
+ */
+fn push_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    let len = u32::try_from(bytes.len()).expect("key or value longer than proto-max-bulk-len");
+    data.extend_from_slice(&len.to_le_bytes());
+    data.extend_from_slice(bytes);
+}
+
+/**
+ * Decode a snapshot into a fresh store. Entries whose expiry time has passed
+ * while the server was down are dropped.
+ */
+pub fn deserialize(data: &[u8]) -> io::Result<Store> {
+    if data.len() < MAGIC.len() + 1 + 1 + 4 || &data[..MAGIC.len()] != MAGIC {
+        return Err(corrupted("not a keyvy snapshot"));
+    }
+    if data[MAGIC.len()] != VERSION {
+        return Err(corrupted("unsupported snapshot version"));
+    }
+
+    // Verify the checksum before looking at any entry, so a truncated file
+    // is rejected as a whole instead of being loaded halfway
+    let (body, checksum) = data.split_at(data.len() - 4);
+    if crc32(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(corrupted(
+            "checksum mismatch, the file is truncated or damaged",
+        ));
+    }
+
+    let mut reader = Reader {
+        data: body,
+        pos: MAGIC.len() + 1,
+    };
+    let now_millis = store::to_unix_millis(Instant::now());
+    let mut store = Store::new();
+
+    loop {
+        match reader.u8()? {
+            ENTRY => {
+                let expires_at = reader.u64()?;
+                let key = reader.bytes()?;
+                let value = reader.bytes()?;
+
+                let expires_at = match expires_at {
+                    0 => None,
+                    at if at <= now_millis => continue,
+                    at => Some(store::from_unix_millis(at)),
+                };
+                store.shard_mut(key).insert(
+                    key.to_vec(),
+                    CacheEntry {
+                        expires_at,
+                        value: value.to_vec(),
+                    },
+                );
+            }
+            EOF => break,
+            _ => return Err(corrupted("unknown record type")),
+        }
+    }
+
+    if reader.pos != body.len() {
+        return Err(corrupted("trailing data after end of snapshot"));
+    }
+    Ok(store)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(corrupted("unexpected end of snapshot"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        self.take(len as usize)
+    }
+}
+
+/**
+ * Write a file through a temporary sibling and rename it into place, so the
+ * previous version stays intact until the new one is fully on disk.
+ */
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let mut file = File::create(&temp)?;
+    let written = file.write_all(data).and_then(|_| file.sync_all());
+    if let Err(e) = written {
+        fs::remove_file(&temp).ok();
+        return Err(e);
+    }
+    fs::rename(&temp, path)
+}
+
+/**
+ * Whether a background save is running. A foreground save must not race it
+ * for the same file.
+ */
+pub fn background_save_in_progress() -> bool {
+    BGSAVE_IN_PROGRESS.load(Ordering::SeqCst)
+}
+
+/**
+ * Save the keyspace in the foreground.
+ */
+pub fn save(store: &Store, path: &Path) -> io::Result<()> {
+    write_atomically(path, &serialize(&store.read_all()))?;
+    log(LogLevel::Notice, "DB saved on disk");
+    Ok(())
+}
+
+/**
+ * Start saving the keyspace in a background thread. The thread encodes it
+ * under the store's read lock and then writes and syncs the file unlocked, so
+ * the connection that asked for the save isn't held up by either.
+ */
+pub fn background_save(store: &Arc<Store>, path: &Path) -> Result<(), String> {
+    if BGSAVE_IN_PROGRESS.swap(true, Ordering::SeqCst) {
+        return Err("Background save already in progress".to_string());
+    }
+
+    let store = Arc::clone(store);
+    let path = path.to_path_buf();
+    log(LogLevel::Notice, "Background saving started");
+
+    thread::spawn(move || {
+        let data = serialize(&store.read_all());
+        match write_atomically(&path, &data) {
+            Ok(()) => log(
+                LogLevel::Notice,
+                "Background saving terminated with success",
+            ),
+            Err(e) => log(
+                LogLevel::Warning,
+                &format!("Background saving error: {}", e),
+            ),
+        }
+        BGSAVE_IN_PROGRESS.store(false, Ordering::SeqCst);
+    });
+
+    Ok(())
+}
+
+/**
+ * Load the snapshot at `path`. A missing file yields an empty store.
+ */
+pub fn load(path: &Path) -> io::Result<Store> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Store::new()),
+        Err(e) => return Err(e),
+    };
+
+    let started = Instant::now();
+    let store = deserialize(&data)?;
+    log(
+        LogLevel::Notice,
+        &format!(
+            "DB loaded from disk: {} keys in {:.3} seconds",
+            store.read_all().len(),
+            started.elapsed().as_secs_f64()
+        ),
+    );
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{entry, TempFile};
+
+    /**
+     * Add the checksum to a hand-built snapshot body
+     */
+    fn sealed(mut body: Vec<u8>) -> Vec<u8> {
+        let checksum = crc32(&body);
+        body.extend_from_slice(&checksum.to_le_bytes());
+        body
+    }
+
+    fn header() -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        data
+    }
+
+    #[test]
+    fn crc32_matches_reference_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trips_keys_values_and_expiry() {
+        let store = Store::new();
+        let later = Instant::now() + Duration::from_secs(3600);
+        for (key, value, expires_at) in [
+            (&b"plain"[..], &b"value"[..], None),
+            (b"\xff\x00binary", b"\x00\r\n", Some(later)),
+            (b"", b"", None),
+        ] {
+            store
+                .write(key)
+                .insert(key.to_vec(), entry(value, expires_at));
+        }
+
+        let loaded = deserialize(&serialize(&store.read_all())).unwrap();
+        assert_eq!(loaded.read_all().len(), 3);
+        let shard = loaded.read(b"\xff\x00binary");
+        let binary = shard.get(b"\xff\x00binary").unwrap();
+        assert_eq!(binary.value, b"\x00\r\n");
+        let expires_at = binary.expires_at.unwrap();
+        assert!(expires_at.max(later) - expires_at.min(later) < Duration::from_millis(5));
+        assert_eq!(
+            loaded.read(b"plain").get(b"plain").unwrap().expires_at,
+            None
+        );
+    }
+
+    #[test]
+    fn skips_expired_entries() {
+        let store = Store::new();
+        let past = Instant::now() - Duration::from_millis(1);
+        store
+            .write(b"gone")
+            .insert(b"gone".to_vec(), entry(b"v", Some(past)));
+        store
+            .write(b"kept")
+            .insert(b"kept".to_vec(), entry(b"v", None));
+
+        let loaded = deserialize(&serialize(&store.read_all())).unwrap();
+        assert_eq!(loaded.read_all().len(), 1);
+        assert!(loaded.read(b"kept").get(b"kept").is_some());
+    }
+
+    #[test]
+    fn drops_entries_that_expired_while_saved() {
+        let mut body = header();
+        body.push(ENTRY);
+        body.extend_from_slice(&1_u64.to_le_bytes());
+        push_bytes(&mut body, b"old");
+        push_bytes(&mut body, b"v");
+        body.push(EOF);
+
+        assert_eq!(deserialize(&sealed(body)).unwrap().read_all().len(), 0);
+    }
+
+    #[test]
+    fn rejects_damaged_or_truncated_files() {
+        let store = Store::new();
+        store
+            .write(b"k")
+            .insert(b"k".to_vec(), entry(b"value", None));
+        let data = serialize(&store.read_all());
+
+        for end in 0..data.len() {
+            assert!(deserialize(&data[..end]).is_err(), "truncated to {}", end);
+        }
+        for i in 0..data.len() {
+            let mut damaged = data.clone();
+            damaged[i] ^= 0x01;
+            assert!(deserialize(&damaged).is_err(), "byte {} flipped", i);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_versions_and_records() {
+        let mut wrong_version = MAGIC.to_vec();
+        wrong_version.push(VERSION + 1);
+        wrong_version.push(EOF);
+        assert!(deserialize(&sealed(wrong_version)).is_err());
+
+        let mut unknown_record = header();
+        unknown_record.push(0x02);
+        assert!(deserialize(&sealed(unknown_record)).is_err());
+
+        let mut trailing = header();
+        trailing.extend_from_slice(&[EOF, 0]);
+        assert!(deserialize(&sealed(trailing)).is_err());
+
+        let mut length_past_end = header();
+        length_past_end.push(ENTRY);
+        length_past_end.extend_from_slice(&0_u64.to_le_bytes());
+        length_past_end.extend_from_slice(&u32::MAX.to_le_bytes());
+        length_past_end.push(EOF);
+        assert!(deserialize(&sealed(length_past_end)).is_err());
+    }
+
+    #[test]
+    fn accepts_an_empty_keyspace() {
+        let mut body = header();
+        body.push(EOF);
+        assert_eq!(deserialize(&sealed(body)).unwrap().read_all().len(), 0);
+    }
+
+    #[test]
+    fn concurrent_writes_leave_one_complete_file() {
+        let file = TempFile::new("concurrent-save", b"");
+        let versions: Vec<Vec<u8>> = (0..8_u8).map(|i| vec![i; 256 * 1024]).collect();
+
+        thread::scope(|scope| {
+            for data in &versions {
+                let path = file.path();
+                scope.spawn(move || write_atomically(path, data).unwrap());
+            }
+        });
+
+        let written = fs::read(file.path()).unwrap();
+        assert!(versions.contains(&written));
+    }
+}
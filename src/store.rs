@@ -0,0 +1,399 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::CacheEntry;
+
+/**
+ * Rough per-key bookkeeping cost (hash table slot, allocation headers, expiry)
+ * added on top of the key and value bytes when estimating memory usage.
+ */
+const ENTRY_OVERHEAD: usize = 64;
+
+/**
+ * Number of independently locked parts the keyspace is split into
+ */
+pub const SHARDS: usize = 16;
+
+/**
+ * Convert a monotonic deadline into wall-clock Unix time in milliseconds
+ */
+pub fn to_unix_millis(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let unix_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let unix = if instant >= now {
+        unix_now + (instant - now)
+    } else {
+        unix_now.saturating_sub(now - instant)
+    };
+    // Round rather than truncate, the two clock readings above are a few
+    // microseconds apart and must not cost a whole millisecond
+    ((unix.as_micros() + 500) / 1000) as u64
+}
+
+/**
+ * Convert wall-clock Unix time in milliseconds into a monotonic deadline
+ */
+pub fn from_unix_millis(millis: u64) -> Instant {
+    let now = Instant::now();
+    let target = Duration::from_millis(millis);
+    let unix_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    if target >= unix_now {
+        now + (target - unix_now)
+    } else {
+        now.checked_sub(unix_now - target).unwrap_or(now)
+    }
+}
+
+/**
+ * The shard `key` lives in. The hash is seeded once per process, so clients
+ * can't aim all their keys at one shard.
+ */
+pub fn shard_index(key: &[u8]) -> usize {
+    static HASHER: OnceLock<RandomState> = OnceLock::new();
+    (HASHER.get_or_init(RandomState::new).hash_one(key) % SHARDS as u64) as usize
+}
+
+fn entry_size(key: &[u8], entry: &CacheEntry) -> usize {
+    key.len() + entry.value.len() + ENTRY_OVERHEAD
+}
+
+/**
+ * One part of the keyspace. Wraps the entry map so the estimated memory usage
+ * and the expiry index stay in sync with every insert and removal.
+ */
+pub struct Shard {
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    /// Keys with an expiry, ordered by when they expire
+    expiries: BTreeSet<(Instant, Vec<u8>)>,
+    /// Memory estimate of the whole store, shared by all its shards
+    used_memory: Arc<AtomicUsize>,
+}
+
+impl Shard {
+    fn new(used_memory: Arc<AtomicUsize>) -> Shard {
+        Shard {
+            entries: HashMap::new(),
+            expiries: BTreeSet::new(),
+            used_memory,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&CacheEntry> {
+        self.entries.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &CacheEntry)> {
+        self.entries.iter()
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, entry: CacheEntry) -> Option<CacheEntry> {
+        self.used_memory
+            .fetch_add(entry_size(&key, &entry), Ordering::Relaxed);
+        let expires_at = entry.expires_at;
+        let previous = self.entries.insert(key.clone(), entry);
+        // Unindex before indexing, the old and new expiry may be the same
+        // instant (KEEPTTL)
+        if let Some(previous) = &previous {
+            self.used_memory
+                .fetch_sub(entry_size(&key, previous), Ordering::Relaxed);
+            self.unindex(&key, previous.expires_at);
+        }
+        if let Some(expiration) = expires_at {
+            self.expiries.insert((expiration, key));
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<CacheEntry> {
+        let removed = self.entries.remove(key);
+        if let Some(entry) = &removed {
+            self.used_memory
+                .fetch_sub(entry_size(key, entry), Ordering::Relaxed);
+            self.unindex(key, entry.expires_at);
+        }
+        removed
+    }
+
+    /**
+     * Change when `key` expires. Returns false if the key does not exist.
+     */
+    pub fn set_expires_at(&mut self, key: &[u8], expires_at: Option<Instant>) -> bool {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        let previous = std::mem::replace(&mut entry.expires_at, expires_at);
+        if previous != expires_at {
+            self.unindex(key, previous);
+            if let Some(expiration) = expires_at {
+                self.expiries.insert((expiration, key.to_vec()));
+            }
+        }
+        true
+    }
+
+    /**
+     * Add `data` to the end of the value of `key`, creating the key without
+     * expiry if it does not exist. Returns the new length of the value.
+     */
+    pub fn append(&mut self, key: &[u8], data: &[u8]) -> usize {
+        self.update_value(key, |value| value.extend_from_slice(data))
+    }
+
+    /**
+     * Overwrite the value of `key` with `data` starting at `offset`, padding
+     * it with zero bytes if it is shorter than `offset`. Creates the key
+     * without expiry if it does not exist. Returns the new length of the value.
+     */
+    pub fn set_range(&mut self, key: &[u8], offset: usize, data: &[u8]) -> usize {
+        self.update_value(key, |value| {
+            let end = offset + data.len();
+            if value.len() < end {
+                value.resize(end, 0);
+            }
+            value[offset..end].copy_from_slice(data);
+        })
+    }
+
+    /**
+     * Change the value of `key` in place rather than copying it into a new
+     * entry, so growing a large value one piece at a time stays cheap
+     */
+    fn update_value(&mut self, key: &[u8], update: impl FnOnce(&mut Vec<u8>)) -> usize {
+        if !self.entries.contains_key(key) {
+            let entry = CacheEntry {
+                expires_at: None,
+                value: Vec::new(),
+            };
+            self.insert(key.to_vec(), entry);
+        }
+        let value = &mut self.entries.get_mut(key).expect("key was just added").value;
+        let before = value.len();
+        update(value);
+        let after = value.len();
+        if after >= before {
+            self.used_memory
+                .fetch_add(after - before, Ordering::Relaxed);
+        } else {
+            self.used_memory
+                .fetch_sub(before - after, Ordering::Relaxed);
+        }
+        after
+    }
+
+    fn unindex(&mut self, key: &[u8], expires_at: Option<Instant>) {
+        if let Some(expiration) = expires_at {
+            // The index holds owned tuples, so the lookup needs one as well
+            self.expiries.remove(&(expiration, key.to_vec()));
+        }
+    }
+
+    /**
+     * Remove at most `limit` keys whose expiry time is not after `now`, the
+     * earliest first. Returns the removed keys.
+     */
+    pub fn remove_expired(&mut self, now: Instant, limit: usize) -> Vec<Vec<u8>> {
+        let mut expired = Vec::new();
+        while expired.len() < limit {
+            match self.expiries.first() {
+                Some((expiration, _)) if *expiration <= now => {}
+                _ => break,
+            }
+            let (_, key) = self.expiries.pop_first().unwrap();
+            if let Some(entry) = self.entries.remove(&key) {
+                self.used_memory
+                    .fetch_sub(entry_size(&key, &entry), Ordering::Relaxed);
+            }
+            expired.push(key);
+        }
+        expired
+    }
+}
+
+/**
+ * The keyspace, split into `SHARDS` shards that are locked independently so
+ * requests for keys in different shards don't wait for each other. A key
+ * always lives in the shard picked by its hash.
+ *
+ * Whenever more than one shard is locked at a time, the locks are taken in
+ * ascending shard order, so concurrent multi-key requests can't deadlock.
+ */
+pub struct Store {
+    shards: Vec<RwLock<Shard>>,
+    used_memory: Arc<AtomicUsize>,
+}
+
+impl Store {
+    pub fn new() -> Store {
+        let used_memory = Arc::new(AtomicUsize::new(0));
+        Store {
+            shards: (0..SHARDS)
+                .map(|_| RwLock::new(Shard::new(Arc::clone(&used_memory))))
+                .collect(),
+            used_memory,
+        }
+    }
+
+    /**
+     * Lock the shard holding `key` for reading
+     */
+    pub fn read(&self, key: &[u8]) -> RwLockReadGuard<'_, Shard> {
+        self.shards[shard_index(key)].read().unwrap()
+    }
+
+    /**
+     * Lock the shard holding `key` for writing
+     */
+    pub fn write(&self, key: &[u8]) -> RwLockWriteGuard<'_, Shard> {
+        self.shards[shard_index(key)].write().unwrap()
+    }
+
+    /**
+     * Lock every shard holding one of `keys` for writing
+     */
+    pub fn write_many(&self, keys: &[Vec<u8>]) -> ShardsWriteGuard<'_> {
+        let mut indexes: Vec<usize> = keys.iter().map(|key| shard_index(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        ShardsWriteGuard {
+            shards: indexes
+                .into_iter()
+                .map(|index| (index, self.shards[index].write().unwrap()))
+                .collect(),
+        }
+    }
+
+    /**
+     * Lock the whole keyspace for reading, for a consistent view across shards
+     */
+    pub fn read_all(&self) -> StoreReadGuard<'_> {
+        StoreReadGuard {
+            store: self,
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| shard.read().unwrap())
+                .collect(),
+        }
+    }
+
+    /**
+     * Lock a single shard by its index, `0..SHARDS`, for reading
+     */
+    pub fn read_shard(&self, index: usize) -> RwLockReadGuard<'_, Shard> {
+        self.shards[index].read().unwrap()
+    }
+
+    /**
+     * Lock a single shard by its index, `0..SHARDS`
+     */
+    pub fn write_shard(&self, index: usize) -> RwLockWriteGuard<'_, Shard> {
+        self.shards[index].write().unwrap()
+    }
+
+    /**
+     * The shard holding `key`, without locking. Only possible while nothing
+     * else can reach the store, e.g. while loading it.
+     */
+    pub fn shard_mut(&mut self, key: &[u8]) -> &mut Shard {
+        let index = shard_index(key);
+        self.shards[index].get_mut().unwrap()
+    }
+
+    /**
+     * Estimated number of bytes held by the keyspace
+     */
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+}
+
+/**
+ * Write locks on the shards holding a set of keys, see `Store::write_many`
+ */
+pub struct ShardsWriteGuard<'a> {
+    shards: Vec<(usize, RwLockWriteGuard<'a, Shard>)>,
+}
+
+impl ShardsWriteGuard<'_> {
+    /**
+     * The locked shard holding `key`. Panics if `key` was not one of the keys
+     * the locks were taken for.
+     */
+    pub fn shard(&mut self, key: &[u8]) -> &mut Shard {
+        let index = shard_index(key);
+        let position = self
+            .shards
+            .iter()
+            .position(|(locked, _)| *locked == index)
+            .expect("shard of the key is not locked");
+        &mut self.shards[position].1
+    }
+}
+
+/**
+ * Read locks on every shard, see `Store::read_all`
+ */
+pub struct StoreReadGuard<'a> {
+    store: &'a Store,
+    shards: Vec<RwLockReadGuard<'a, Shard>>,
+}
+
+impl StoreReadGuard<'_> {
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &CacheEntry)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.entries.len()).sum()
+    }
+
+    /**
+     * Estimated number of bytes held by the keyspace
+     */
+    pub fn used_memory(&self) -> usize {
+        self.store.used_memory()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::entry;
+
+    #[test]
+    fn replacing_with_the_same_expiry_keeps_the_key_indexed() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        let expires_at = Instant::now() + Duration::from_secs(1);
+        shard.insert(b"k".to_vec(), entry(b"old", Some(expires_at)));
+        shard.insert(b"k".to_vec(), entry(b"new", Some(expires_at)));
+
+        let later = expires_at + Duration::from_millis(1);
+        assert_eq!(shard.remove_expired(later, 10), vec![b"k".to_vec()]);
+        assert!(shard.get(b"k").is_none());
+        assert_eq!(store.used_memory(), 0);
+    }
+
+    #[test]
+    fn index_follows_expiry_changes() {
+        let store = Store::new();
+        let mut shard = store.write(b"k");
+        let soon = Instant::now() + Duration::from_secs(1);
+        let later = soon + Duration::from_secs(1);
+        shard.insert(b"k".to_vec(), entry(b"v", Some(soon)));
+        assert!(shard.set_expires_at(b"k", Some(later)));
+        assert!(shard.remove_expired(soon, 10).is_empty());
+
+        shard.insert(b"k".to_vec(), entry(b"v", None));
+        assert!(shard.remove_expired(later, 10).is_empty());
+        assert!(shard.get(b"k").is_some());
+    }
+}
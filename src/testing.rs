@@ -0,0 +1,126 @@
+//! Fixtures shared by the unit tests of several modules
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+
+use crate::acl::Acl;
+use crate::aof::Aof;
+use crate::config::Config;
+use crate::store::Store;
+use crate::{CacheEntry, Server};
+
+/**
+ * A file in the temporary directory, removed again when dropped. Every file
+ * gets its own path, so tests running in parallel never share one.
+ */
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str, contents: &[u8]) -> TempFile {
+        let file = TempFile::reserve(name);
+        fs::write(&file.0, contents).unwrap();
+        file
+    }
+
+    /**
+     * A path for the test itself to create a file at
+     */
+    pub fn reserve(name: &str) -> TempFile {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        TempFile(std::env::temp_dir().join(format!(
+            "keyvy-test-{}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed),
+            name
+        )))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+/**
+ * Split a command into its arguments the way a client would send them
+ */
+pub fn args(words: &[&str]) -> Vec<Vec<u8>> {
+    words.iter().map(|word| word.as_bytes().to_vec()).collect()
+}
+
+pub fn entry(value: &[u8], expires_at: Option<Instant>) -> CacheEntry {
+    CacheEntry {
+        expires_at,
+        value: value.to_vec(),
+    }
+}
+
+/**
+ * A server with an empty keyspace and no AOF, set up by `config`
+ */
+pub fn server(config: Config) -> Arc<Server> {
+    Arc::new(Server {
+        store: Arc::new(Store::new()),
+        acl: RwLock::new(Acl::new(&config.requirepass)),
+        config: RwLock::new(config),
+        tls: RwLock::new(None),
+        aof: Aof::disabled(),
+        shutting_down: AtomicBool::new(false),
+        clients: AtomicUsize::new(0),
+    })
+}
+
+/**
+ * A certificate with its key, both generated for the test
+ */
+pub struct Identity {
+    pub cert: Certificate,
+    pub key: KeyPair,
+}
+
+impl Identity {
+    pub fn ca() -> Identity {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "keyvy test CA");
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        Identity { cert, key }
+    }
+
+    /**
+     * A certificate for `localhost` with `common_name`, signed by `issuer`
+     */
+    pub fn issue(common_name: &str, issuer: &Identity) -> Identity {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &issuer.cert, &issuer.key).unwrap();
+        Identity { cert, key }
+    }
+
+    pub fn cert_file(&self, name: &str) -> TempFile {
+        TempFile::new(&format!("{}.crt", name), self.cert.pem().as_bytes())
+    }
+
+    pub fn key_file(&self, name: &str) -> TempFile {
+        TempFile::new(
+            &format!("{}.key", name),
+            self.key.serialize_pem().as_bytes(),
+        )
+    }
+}
@@ -0,0 +1,242 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::Arc;
+
+use mio::net::TcpStream;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+use crate::config::{Config, TlsAuthClients};
+
+/**
+ * Build the TLS settings from the certificate and key files named in
+ * `config`. Runs at startup and again whenever one of the `tls-*` settings is
+ * changed with CONFIG SET, so renewed certificates are picked up without a
+ * restart.
+ */
+pub fn load(config: &Config) -> Result<Arc<ServerConfig>, String> {
+    if config.tls_cert_file.is_empty() || config.tls_key_file.is_empty() {
+        return Err("tls-cert-file and tls-key-file must be set for TLS".to_string());
+    }
+    let certs = load_certs(&config.tls_cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(&config.tls_key_file)
+        .map_err(|e| format!("Can't load key {}: {}", config.tls_key_file, e))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match config.tls_auth_clients {
+        TlsAuthClients::No => builder.with_no_client_auth(),
+        auth => {
+            if config.tls_ca_cert_file.is_empty() {
+                return Err("tls-ca-cert-file must be set to check client certificates".to_string());
+            }
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&config.tls_ca_cert_file)? {
+                roots.add(cert).map_err(|e| {
+                    format!(
+                        "Invalid CA certificate in {}: {}",
+                        config.tls_ca_cert_file, e
+                    )
+                })?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if auth == TlsAuthClients::Optional {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+        }
+    };
+    builder
+        .with_single_cert(certs, key)
+        .map(Arc::new)
+        .map_err(|e| format!("Invalid certificate or key: {}", e))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Can't load certificates from {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certs)
+}
+
+/**
+ * A non-blocking TLS connection. Reads and writes plaintext like the socket
+ * it wraps and returns `WouldBlock` whenever the socket does, so it can be
+ * served by the same event loop as plain connections.
+ */
+pub struct TlsStream {
+    connection: ServerConnection,
+    socket: TcpStream,
+}
+
+impl TlsStream {
+    pub fn new(socket: TcpStream, config: Arc<ServerConfig>) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            connection: ServerConnection::new(config).map_err(io::Error::other)?,
+            socket,
+        })
+    }
+
+    pub fn socket_mut(&mut self) -> &mut TcpStream {
+        &mut self.socket
+    }
+
+    /**
+     * Whether encrypted data is waiting for the socket to accept it
+     */
+    pub fn wants_write(&self) -> bool {
+        self.connection.wants_write()
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.connection.is_handshaking()
+    }
+
+    /**
+     * Common name of the client's verified certificate, if it sent one
+     */
+    pub fn peer_common_name(&self) -> Option<String> {
+        let cert = self.connection.peer_certificates()?.first()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+        let name = cert.subject().iter_common_name().next()?;
+        name.as_str().ok().map(str::to_string)
+    }
+
+    /**
+     * Send as much of the pending encrypted data as the socket takes
+     */
+    fn write_pending(&mut self) -> io::Result<()> {
+        while self.connection.wants_write() {
+            if self.connection.write_tls(&mut self.socket)? == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Like `write_pending`, but a full socket is not an error: what's left
+     * goes out once the socket becomes writable again.
+     */
+    fn try_write_pending(&mut self) -> io::Result<()> {
+        match self.write_pending() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.connection.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            // Nothing decrypted is buffered, so get more from the socket
+            if self.connection.read_tls(&mut self.socket)? == 0 {
+                return Ok(0);
+            }
+            let processed = self.connection.process_new_packets();
+            // Handshake messages and alerts go out right away, whether or not
+            // the client is sent anything else
+            self.try_write_pending()?;
+            processed.map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // rustls only buffers a limited amount, make room first
+        self.try_write_pending()?;
+        let written = self.connection.writer().write(buf)?;
+        self.try_write_pending()?;
+        if written == 0 && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Identity, TempFile};
+
+    fn path(file: &TempFile) -> String {
+        file.path().to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn load_needs_a_certificate_and_key() {
+        let ca = Identity::ca();
+        let server = Identity::issue("server", &ca);
+        let (cert, key) = (server.cert_file("server"), server.key_file("server"));
+        let empty = TempFile::new("empty.crt", b"");
+        let config = |cert: &str, key: &str| Config {
+            tls_cert_file: cert.to_string(),
+            tls_key_file: key.to_string(),
+            ..Config::default()
+        };
+
+        assert!(load(&config(&path(&cert), &path(&key))).is_ok());
+        for (cert, key, error) in [
+            ("", path(&key), "tls-cert-file and tls-key-file must be set"),
+            (
+                &path(&cert),
+                String::new(),
+                "tls-cert-file and tls-key-file must be set",
+            ),
+            (
+                "/nonexistent/server.crt",
+                path(&key),
+                "Can't load certificates",
+            ),
+            (&path(&empty), path(&key), "No certificates found"),
+            (
+                &path(&cert),
+                "/nonexistent/server.key".to_string(),
+                "Can't load key",
+            ),
+            (&path(&cert), path(&cert), "Can't load key"),
+        ] {
+            let e = load(&config(cert, &key)).unwrap_err();
+            assert!(e.contains(error), "{:?}, {:?}: {}", cert, key, e);
+        }
+    }
+
+    #[test]
+    fn checking_client_certificates_needs_a_ca() {
+        let ca = Identity::ca();
+        let server = Identity::issue("server", &ca);
+        let (cert, key) = (server.cert_file("server"), server.key_file("server"));
+        let ca_cert = ca.cert_file("ca");
+
+        for auth in [TlsAuthClients::Yes, TlsAuthClients::Optional] {
+            let mut config = Config {
+                tls_cert_file: path(&cert),
+                tls_key_file: path(&key),
+                tls_auth_clients: auth,
+                ..Config::default()
+            };
+            let e = load(&config).unwrap_err();
+            assert!(e.contains("tls-ca-cert-file must be set"), "{}", e);
+
+            config.tls_ca_cert_file = "/nonexistent/ca.crt".to_string();
+            assert!(load(&config).is_err());
+            config.tls_ca_cert_file = path(&ca_cert);
+            assert!(load(&config).is_ok());
+        }
+    }
+}